@@ -0,0 +1,1127 @@
+//! Importing and exporting Anki packages (`.apkg` and `.colpkg`) - a zip of an SQLite collection and its media
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use log::{info, warn};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Deserialize;
+
+use crate::{
+    data::{Color, FlashcardSet, RichText},
+    escape,
+    media::{self, MediaKind},
+    rich_text::{Block, Document, Script, Span},
+    schedule::{Direction, Grade, ReviewLog, ReviewState, StudyDirection, DAY},
+};
+
+/// Collection files, best first. `collection.anki2` in newer packages only says to update Anki.
+const COLLECTIONS: [&str; 2] = ["collection.anki21", "collection.anki2"];
+/// Collection file of packages made by Anki 2.1.50 and later, which can't be read yet
+const COMPRESSED_COLLECTION: &str = "collection.anki21b";
+/// Separates the fields of a note
+const FIELD_SEPARATOR: char = '\u{1f}';
+/// Note type kind used for cloze deletions
+const CLOZE: u8 = 1;
+/// Names of the card templates of exported note types, which say which way round their cards are
+const FORWARD_TEMPLATE: &str = "Front → back";
+const REVERSE_TEMPLATE: &str = "Back → front";
+
+/// What was read from a package
+pub struct Package {
+    /// One set per deck that has cards
+    pub sets: Vec<FlashcardSet>,
+    /// Images and sounds the cards use that couldn't be brought across - missing from the package,
+    /// or of a kind that can't be shown
+    pub skipped_media: usize,
+}
+
+/// The parts of a note type that matter here
+#[derive(Deserialize)]
+struct NoteType {
+    #[serde(rename = "type", default)]
+    kind: u8,
+    #[serde(default)]
+    tmpls: Vec<serde_json::Value>,
+}
+
+impl NoteType {
+    /// Which way round the card made by template `ord` is, or `None` if a flashcard can't hold it
+    fn direction(&self, ord: i64) -> Option<Direction> {
+        let template = self
+            .tmpls
+            .iter()
+            .find(|t| t.get("ord").and_then(serde_json::Value::as_i64) == Some(ord))
+            .or_else(|| self.tmpls.get(usize::try_from(ord).ok()?));
+        match template.and_then(|t| t.get("name")).and_then(serde_json::Value::as_str) {
+            Some(FORWARD_TEMPLATE) => Some(Direction::Forward),
+            Some(REVERSE_TEMPLATE) => Some(Direction::Reverse),
+            // The second template of a two-template note type is the reversed card
+            _ => match ord {
+                0 => Some(Direction::Forward),
+                1 if self.tmpls.len() == 2 => Some(Direction::Reverse),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Deck {
+    name: String,
+}
+
+/// Scheduling of one card
+struct Card {
+    id: i64,
+    note: i64,
+    deck: i64,
+    ord: i64,
+    kind: i64,
+    due: i64,
+    interval: i64,
+    factor: i64,
+    reps: i64,
+    lapses: i64,
+}
+
+/// One entry of a card's review history
+struct Review {
+    /// Milliseconds since the unix epoch
+    time: i64,
+    /// Which button was pressed, 1 to 4
+    ease: i64,
+    interval: i64,
+    last_interval: i64,
+    factor: i64,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    invalid(format!("couldn't read the collection: {}", e))
+}
+
+/// Reads an Anki package, adding the images and sounds its cards use to the media store in `media_dir`
+pub fn import(path: &Path, media_dir: &Path) -> io::Result<Package> {
+    info!("Importing Anki package {}", path.display());
+    let mut zip = zip::ZipArchive::new(File::open(path)?).map_err(|e| invalid(e.to_string()))?;
+    let name = COLLECTIONS
+        .iter()
+        .find(|name| zip.by_name(name).is_ok())
+        .ok_or_else(|| invalid("this isn't an Anki package - there's no collection in it"))?;
+    if *name == "collection.anki2" && zip.by_name(COMPRESSED_COLLECTION).is_ok() {
+        return Err(invalid(
+            "this package was made by a newer Anki - export it again with \"Support older Anki versions\" ticked",
+        ));
+    }
+    // SQLite needs the collection in a file of its own
+    let mut collection = tempfile::NamedTempFile::new()?;
+    {
+        let mut entry = zip.by_name(name).map_err(|e| invalid(e.to_string()))?;
+        let mut bytes = vec![];
+        entry.read_to_end(&mut bytes)?;
+        collection.write_all(&bytes)?;
+        collection.flush()?;
+    }
+    // The media files are numbered, with a list of their names
+    let numbered: HashMap<String, String> = match zip.by_name("media") {
+        Ok(mut entry) => {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            serde_json::from_str(&json).unwrap_or_default()
+        }
+        Err(_) => HashMap::new(),
+    };
+    let entries: HashMap<String, String> = numbered.into_iter().map(|(number, name)| (name, number)).collect();
+    // Each file is only stored once, however many cards use it
+    let mut stored: HashMap<String, Option<String>> = HashMap::new();
+    let mut media = |name: &str, kind: MediaKind| -> Option<String> {
+        let entry = entries.get(name).or_else(|| entries.get(&percent_decode(name)))?;
+        stored
+            .entry(entry.clone())
+            .or_insert_with(|| {
+                let extension = name.rsplit_once('.')?.1.to_ascii_lowercase();
+                let allowed = match kind {
+                    MediaKind::Image => &media::IMAGE_EXTENSIONS[..],
+                    MediaKind::Audio => &media::AUDIO_EXTENSIONS[..],
+                };
+                if !allowed.contains(&extension.as_str()) {
+                    return None;
+                }
+                let mut bytes = vec![];
+                zip.by_name(entry).ok()?.read_to_end(&mut bytes).ok()?;
+                media::store(media_dir, &bytes, &extension)
+                    .map_err(|e| warn!("Could not add {} to the media store: {}", name, e))
+                    .ok()
+            })
+            .clone()
+    };
+    let connection =
+        Connection::open_with_flags(collection.path(), OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sql_error)?;
+    read_collection(&connection, &mut media).map_err(sql_error)
+}
+
+/// Reads the decks, notes, cards and review history out of a collection.
+/// `media` gives the name in the media store of a file the notes use, if it can be brought across.
+fn read_collection(
+    connection: &Connection,
+    media: &mut dyn FnMut(&str, MediaKind) -> Option<String>,
+) -> rusqlite::Result<Package> {
+    let (created, models, decks): (i64, String, String) =
+        connection.query_row("SELECT crt, models, decks FROM col", [], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+    let models: HashMap<String, NoteType> = serde_json::from_str(&models).unwrap_or_default();
+    let decks: HashMap<String, Deck> = serde_json::from_str(&decks).unwrap_or_default();
+
+    let mut notes = HashMap::new();
+    let mut note_query = connection.prepare("SELECT id, mid, flds FROM notes ORDER BY id")?;
+    let note_rows = note_query.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?, r.get::<_, String>(2)?)))?;
+    for row in note_rows {
+        let (id, model, fields) = row?;
+        notes.insert(id, (model, fields));
+    }
+
+    // Cards in filtered decks remember their original deck and due date
+    let mut card_query = connection.prepare(
+        "SELECT id, nid, CASE WHEN odid != 0 THEN odid ELSE did END, ord, type,
+                CASE WHEN odid != 0 THEN odue ELSE due END, ivl, factor, reps, lapses
+         FROM cards ORDER BY nid, ord",
+    )?;
+    let cards = card_query
+        .query_map([], |r| {
+            Ok(Card {
+                id: r.get(0)?,
+                note: r.get(1)?,
+                deck: r.get(2)?,
+                ord: r.get(3)?,
+                kind: r.get(4)?,
+                due: r.get(5)?,
+                interval: r.get(6)?,
+                factor: r.get(7)?,
+                reps: r.get(8)?,
+                lapses: r.get(9)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut history: HashMap<i64, Vec<Review>> = HashMap::new();
+    let mut log_query = connection.prepare("SELECT cid, id, ease, ivl, lastIvl, factor FROM revlog ORDER BY id")?;
+    let log_rows = log_query.query_map([], |r| {
+        let review = Review {
+            time: r.get(1)?,
+            ease: r.get(2)?,
+            interval: r.get(3)?,
+            last_interval: r.get(4)?,
+            factor: r.get(5)?,
+        };
+        Ok((r.get::<_, i64>(0)?, review))
+    })?;
+    for row in log_rows {
+        let (card, log) = row?;
+        history.entry(card).or_default().push(log);
+    }
+
+    // Sets in the order their decks are first seen, with where each note's card went
+    let mut sets: Vec<(i64, FlashcardSet)> = vec![];
+    // Whether each set has cards studied forwards, and backwards
+    let mut studied: Vec<(bool, bool)> = vec![];
+    let mut placed: HashMap<(i64, i64), (usize, u64)> = HashMap::new();
+    let mut missing_media = HashSet::new();
+    for card in &cards {
+        let (model, fields) = match notes.get(&card.note) {
+            Some(note) => note,
+            None => continue,
+        };
+        let model = models.get(&model.to_string());
+        let cloze = model.map_or(false, |m| m.kind == CLOZE);
+        let direction = match model {
+            // Each gap of a cloze note is a card of its own
+            _ if cloze => Some(Direction::Forward),
+            Some(model) => model.direction(card.ord),
+            None => (card.ord == 0).then_some(Direction::Forward),
+        };
+        let direction = match direction {
+            Some(direction) => direction,
+            None => continue,
+        };
+        // Both directions of a note are one flashcard, but every gap of a cloze note is another
+        let key = (card.note, if cloze { card.ord } else { 0 });
+        let (set_index, card_id) = match placed.get(&key) {
+            Some(&placed) => placed,
+            None => {
+                let set_index = match sets.iter().position(|(deck, _)| *deck == card.deck) {
+                    Some(index) => index,
+                    None => {
+                        let name = decks
+                            .get(&card.deck.to_string())
+                            .map_or_else(|| format!("Anki deck {}", card.deck), |d| d.name.replace("::", " / "));
+                        sets.push((card.deck, FlashcardSet::new(name)));
+                        studied.push((false, false));
+                        sets.len() - 1
+                    }
+                };
+                let (mut front, mut back) = if cloze {
+                    let (front, back) = cloze_sides(fields, card.ord + 1);
+                    (RichText::plaintext(front), RichText::plaintext(back))
+                } else {
+                    note_sides(fields)
+                };
+                // The first field is on the front, and the rest on the back - the back of a cloze shows all of them
+                let (first, rest) = fields.split_once(FIELD_SEPARATOR).unwrap_or((fields, ""));
+                attach_media(&mut front, first, media, &mut missing_media);
+                attach_media(&mut back, if cloze { fields } else { rest }, media, &mut missing_media);
+                let card_id = sets[set_index].1.add(front, back).id();
+                placed.insert(key, (set_index, card_id));
+                (set_index, card_id)
+            }
+        };
+        let set = &mut sets[set_index].1;
+        match direction {
+            Direction::Forward => studied[set_index].0 = true,
+            Direction::Reverse => studied[set_index].1 = true,
+        }
+        let flashcard = &mut set[card_id];
+        let state = review_state(card, created);
+        match direction {
+            Direction::Forward => flashcard.review = state,
+            Direction::Reverse => flashcard.reverse_review = state,
+        }
+        for review in history.get(&card.id).into_iter().flatten() {
+            flashcard.history.push(ReviewLog {
+                time: (review.time / 1000) as u64,
+                grade: grade(review.ease),
+                direction,
+                last_interval: days(review.last_interval),
+                interval: days(review.interval),
+                ease: review.factor as u32,
+            });
+        }
+        flashcard.history.sort_by_key(|log| log.time);
+    }
+    let sets = sets.into_iter().zip(studied).map(|((_, mut set), studied)| {
+        set.direction = match studied {
+            (true, true) => StudyDirection::Both,
+            (false, true) => StudyDirection::Reverse,
+            _ => StudyDirection::Forward,
+        };
+        set
+    });
+    Ok(Package {
+        sets: sets.collect(),
+        skipped_media: missing_media.len(),
+    })
+}
+
+/// Converts Anki's scheduling of a card
+fn review_state(card: &Card, created: i64) -> ReviewState {
+    let mut state = ReviewState::default();
+    // New cards keep the default state
+    if card.kind == 0 {
+        return state;
+    }
+    if card.factor > 0 {
+        state.ease = card.factor as u32;
+    }
+    state.interval = days(card.interval);
+    // Cards being learnt are due at a time, reviews on a day counted from when the collection was made
+    state.due = if card.due > 1_000_000_000 {
+        card.due as u64
+    } else {
+        (created + card.due.max(0) * DAY as i64) as u64
+    };
+    state.reviews = card.reps.max(1) as u32;
+    state.lapses = card.lapses.max(0) as u32;
+    state
+}
+
+/// Anki intervals are in days, or negative seconds while learning
+fn days(interval: i64) -> u32 {
+    interval.max(0) as u32
+}
+
+/// Anki's answer buttons, 1 to 4
+const fn grade(ease: i64) -> Grade {
+    match ease {
+        1 => Grade::Again,
+        2 => Grade::Hard,
+        4 => Grade::Easy,
+        _ => Grade::Good,
+    }
+}
+
+/// The first field goes on the front, the rest on the back
+fn note_sides(fields: &str) -> (RichText, RichText) {
+    let mut fields = fields.split(FIELD_SEPARATOR);
+    let front = from_field(fields.next().unwrap_or_default());
+    let back: Vec<RichText> = fields.map(from_field).filter(|f| !f.is_empty()).collect();
+    let back = match back.as_slice() {
+        [only] => only.clone(),
+        _ => RichText::plaintext(back.iter().map(|f| f.text.as_str()).collect::<Vec<_>>().join("\n")),
+    };
+    (front, back)
+}
+
+/// Reads a field, keeping its colour if the whole field is coloured (as exported by [`to_field`])
+fn from_field(html: &str) -> RichText {
+    let coloured = html
+        .trim()
+        .strip_prefix("<span style=\"color: ")
+        .and_then(|rest| rest.strip_suffix("</span>"))
+        .and_then(|rest| rest.split_once("\">"))
+        .filter(|(_, inner)| !inner.contains("<span"))
+        .and_then(|(color, inner)| Some((Color::parse(&decode_entities(color))?, inner)));
+    match coloured {
+        Some((color, inner)) => RichText {
+            text: html_to_text(inner),
+            color,
+            ..RichText::empty()
+        },
+        None => RichText::plaintext(html_to_text(html)),
+    }
+}
+
+/// A card of a cloze note shows the text with gap `number` on the front, and filled in on the back.
+/// The note's other gaps are filled in on both sides.
+fn cloze_sides(fields: &str, number: i64) -> (String, String) {
+    let mut fields = fields.split(FIELD_SEPARATOR).map(html_to_text);
+    let text = fields.next().unwrap_or_default();
+    let extra: Vec<String> = fields.filter(|f| !f.is_empty()).collect();
+    let mut front = String::new();
+    let mut back = String::new();
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find("{{c") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        front.push_str(&rest[..start]);
+        back.push_str(&rest[..start]);
+        // {{c1::answer::hint}}
+        let mut parts = rest[start + 2..end].splitn(3, "::");
+        let gap: Option<i64> = parts.next().and_then(|c| c.strip_prefix('c')).and_then(|n| n.parse().ok());
+        let answer = parts.next().unwrap_or_default();
+        match parts.next() {
+            _ if gap != Some(number) => front.push_str(answer),
+            Some(hint) => front.push_str(&format!("[{}]", hint)),
+            None => front.push_str("[...]"),
+        }
+        back.push_str(answer);
+        rest = &rest[end + 2..];
+    }
+    front.push_str(rest);
+    back.push_str(rest);
+    if !extra.is_empty() {
+        back.push('\n');
+        back.push_str(&extra.join("\n"));
+    }
+    (front, back)
+}
+
+/// Turns a field's HTML into plain text - line breaks are kept, other tags and media are dropped
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let tag = rest[start + 1..end].trim_start_matches('/').to_lowercase();
+        let name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or_default();
+        if matches!(name, "br" | "div" | "p" | "li") && !text.is_empty() && !text.ends_with('\n') {
+            text.truncate(text.trim_end_matches(' ').len());
+            text.push('\n');
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    // Sounds are written [sound:file.mp3]
+    while let Some(start) = text.find("[sound:") {
+        match text[start..].find(']') {
+            Some(end) => text.replace_range(start..start + end + 1, ""),
+            None => break,
+        }
+    }
+    decode_entities(&text).trim().to_owned()
+}
+
+/// Names of the images (`<img src="...">`) and sounds (`[sound:...]`) a field uses
+fn field_media(html: &str) -> Vec<(String, MediaKind)> {
+    let mut found = vec![];
+    // ASCII lowercase keeps the positions the same
+    let lower = html.to_ascii_lowercase();
+    let mut rest = 0;
+    while let Some(start) = lower[rest..].find("<img").map(|start| rest + start) {
+        let end = lower[start..].find('>').map_or(lower.len(), |end| start + end);
+        if let Some(src) = attribute(&html[start..end], "src") {
+            found.push((decode_entities(src), MediaKind::Image));
+        }
+        rest = end;
+    }
+    let mut rest = html;
+    while let Some(start) = rest.find("[sound:") {
+        let name = &rest[start + 7..];
+        let end = match name.find(']') {
+            Some(end) => end,
+            None => break,
+        };
+        found.push((decode_entities(&name[..end]), MediaKind::Audio));
+        rest = &name[end..];
+    }
+    found
+}
+
+/// The value of an attribute in an HTML tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let pattern = format!("{}=", name);
+    let mut from = 0;
+    let at = loop {
+        let at = from + lower[from..].find(&pattern)?;
+        // Not the end of another attribute's name, like data-src
+        if lower[..at].ends_with(char::is_whitespace) {
+            break at;
+        }
+        from = at + pattern.len();
+    };
+    let value = tag[at + pattern.len()..].trim_start();
+    match value.chars().next()? {
+        quote @ ('"' | '\'') => value[1..].split(quote).next(),
+        _ => value.split(char::is_whitespace).next().map(|v| v.trim_end_matches('/')),
+    }
+}
+
+/// Adds the images and sounds used in `html` to `text`, by their names in the media store.
+/// Those that can't be brought across are added to `missing`.
+fn attach_media(
+    text: &mut RichText,
+    html: &str,
+    media: &mut dyn FnMut(&str, MediaKind) -> Option<String>,
+    missing: &mut HashSet<String>,
+) {
+    for (name, kind) in field_media(html) {
+        let names = match kind {
+            MediaKind::Image => &mut text.images,
+            MediaKind::Audio => &mut text.audio,
+        };
+        match media(&name, kind) {
+            Some(stored) if !names.contains(&stored) => names.push(stored),
+            Some(_) => {}
+            None => {
+                missing.insert(name);
+            }
+        }
+    }
+}
+
+/// Undoes URL escapes like `%20`, which Anki uses in the names of images
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = text
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decodes the HTML entities Anki uses
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let entity = rest[start + 1..].find(';').map(|end| &rest[start + 1..start + 1 + end]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(|c| c == 'x' || c == 'X') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (character, entity) {
+            (Some(character), Some(entity)) => {
+                decoded.push(character);
+                rest = &rest[start + entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Tables of a collection, as made by Anki 2.1 (schema version 11)
+const SCHEMA: &str = "
+    CREATE TABLE col (
+        id integer PRIMARY KEY, crt integer NOT NULL, mod integer NOT NULL, scm integer NOT NULL,
+        ver integer NOT NULL, dty integer NOT NULL, usn integer NOT NULL, ls integer NOT NULL,
+        conf text NOT NULL, models text NOT NULL, decks text NOT NULL, dconf text NOT NULL, tags text NOT NULL
+    );
+    CREATE TABLE notes (
+        id integer PRIMARY KEY, guid text NOT NULL, mid integer NOT NULL, mod integer NOT NULL,
+        usn integer NOT NULL, tags text NOT NULL, flds text NOT NULL, sfld integer NOT NULL,
+        csum integer NOT NULL, flags integer NOT NULL, data text NOT NULL
+    );
+    CREATE TABLE cards (
+        id integer PRIMARY KEY, nid integer NOT NULL, did integer NOT NULL, ord integer NOT NULL,
+        mod integer NOT NULL, usn integer NOT NULL, type integer NOT NULL, queue integer NOT NULL,
+        due integer NOT NULL, ivl integer NOT NULL, factor integer NOT NULL, reps integer NOT NULL,
+        lapses integer NOT NULL, left integer NOT NULL, odue integer NOT NULL, odid integer NOT NULL,
+        flags integer NOT NULL, data text NOT NULL
+    );
+    CREATE TABLE revlog (
+        id integer PRIMARY KEY, cid integer NOT NULL, usn integer NOT NULL, ease integer NOT NULL,
+        ivl integer NOT NULL, lastIvl integer NOT NULL, factor integer NOT NULL, time integer NOT NULL,
+        type integer NOT NULL
+    );
+    CREATE TABLE graves (usn integer NOT NULL, oid integer NOT NULL, type integer NOT NULL);
+    CREATE INDEX ix_notes_usn ON notes (usn);
+    CREATE INDEX ix_cards_usn ON cards (usn);
+    CREATE INDEX ix_revlog_usn ON revlog (usn);
+    CREATE INDEX ix_cards_nid ON cards (nid);
+    CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+    CREATE INDEX ix_revlog_cid ON revlog (cid);
+    CREATE INDEX ix_notes_csum ON notes (csum);
+";
+/// Anki's default deck, which every collection has
+const DEFAULT_DECK: i64 = 1;
+/// How long a learning step Anki shows for cards we haven't scheduled in days (negative seconds)
+const LEARNING_STEP: i64 = -600;
+
+/// Escapes text for an Anki field, which holds HTML
+fn escape_html(text: &str) -> String {
+    escape::html(text).replace('\n', "<br>")
+}
+
+/// Formatted spans as HTML - formatting becomes tags, and coloured spans are wrapped in styled spans
+fn spans_html(spans: &[Span]) -> String {
+    let mut html = String::new();
+    for span in spans {
+        let style = &span.style;
+        let tags: Vec<&str> = [
+            (style.bold, "b"),
+            (style.italic, "i"),
+            (style.underline, "u"),
+            (style.strike, "s"),
+            (style.code, "code"),
+            (style.script == Script::Sub, "sub"),
+            (style.script == Script::Super, "sup"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, tag)| *tag)
+        .collect();
+        if let Some(color) = &style.color {
+            html.push_str(&format!("<span style=\"color: {}\">", color));
+        }
+        for tag in &tags {
+            html.push_str(&format!("<{}>", tag));
+        }
+        html.push_str(&escape_html(&span.text));
+        for tag in tags.iter().rev() {
+            html.push_str(&format!("</{}>", tag));
+        }
+        if style.color.is_some() {
+            html.push_str("</span>");
+        }
+    }
+    html
+}
+
+/// Formatted text as HTML, with lines between paragraphs and lists as lists
+fn document_html(doc: &Document) -> String {
+    let mut html = String::new();
+    let mut after_paragraph = false;
+    for block in &doc.blocks {
+        match block {
+            Block::Paragraph { spans } => {
+                if after_paragraph {
+                    html.push_str("<br>");
+                }
+                html.push_str(&spans_html(spans));
+                after_paragraph = true;
+            }
+            Block::List { ordered, items } => {
+                let tag = if *ordered { "ol" } else { "ul" };
+                html.push_str(&format!("<{}>", tag));
+                for item in items {
+                    html.push_str(&format!("<li>{}</li>", spans_html(item)));
+                }
+                html.push_str(&format!("</{}>", tag));
+                after_paragraph = false;
+            }
+        }
+    }
+    html
+}
+
+/// A side of a card as an Anki field, followed by its pictures and sounds - coloured text is wrapped in a styled span
+fn to_field(text: &RichText) -> String {
+    let mut html = match &text.doc {
+        Some(doc) => document_html(doc),
+        None => escape_html(&text.text),
+    };
+    // Names in the media store don't need escaping
+    for image in &text.images {
+        html.push_str(&format!("<img src=\"{}\">", image));
+    }
+    for sound in &text.audio {
+        html.push_str(&format!("[sound:{}]", sound));
+    }
+    if text.color == Color::black() {
+        html
+    } else {
+        format!("<span style=\"color: {}\">{}</span>", text.color, html)
+    }
+}
+
+/// A note type with the `Front` and `Back` fields, and a card for each direction
+fn note_type(id: i64, name: &str, directions: &[Direction], now: u64) -> serde_json::Value {
+    let field = |name: &str, ord: usize| {
+        serde_json::json!({
+            "name": name, "ord": ord, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [],
+        })
+    };
+    let templates: Vec<_> = directions
+        .iter()
+        .enumerate()
+        .map(|(ord, direction)| {
+            let (name, question, answer) = match direction {
+                Direction::Forward => (FORWARD_TEMPLATE, "{{Front}}", "{{FrontSide}}<hr id=answer>{{Back}}"),
+                Direction::Reverse => (REVERSE_TEMPLATE, "{{Back}}", "{{FrontSide}}<hr id=answer>{{Front}}"),
+            };
+            serde_json::json!({
+                "name": name, "ord": ord, "qfmt": question, "afmt": answer,
+                "bqfmt": "", "bafmt": "", "did": null, "bfont": "", "bsize": 0,
+            })
+        })
+        .collect();
+    // Which field each card needs to be generated
+    let requirements: Vec<_> = directions
+        .iter()
+        .enumerate()
+        .map(|(ord, direction)| serde_json::json!([ord, "any", [if *direction == Direction::Forward { 0 } else { 1 }]]))
+        .collect();
+    serde_json::json!({
+        "id": id, "name": name, "type": 0, "mod": now, "usn": 0, "sortf": 0, "did": DEFAULT_DECK,
+        "tmpls": templates,
+        "flds": [field("Front", 0), field("Back", 1)],
+        "css": ".card { font-family: arial; font-size: 20px; text-align: center; color: black; background-color: white; }",
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage[utf8]{inputenc}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "latexsvg": false,
+        "req": requirements,
+        "tags": [], "vers": [],
+    })
+}
+
+fn deck(id: i64, name: &str, now: u64) -> serde_json::Value {
+    serde_json::json!({
+        "id": id, "name": name, "mod": now, "usn": 0, "desc": "", "dyn": 0, "conf": 1,
+        "collapsed": false, "browserCollapsed": false, "extendNew": 0, "extendRev": 0,
+        "lrnToday": [0, 0], "revToday": [0, 0], "newToday": [0, 0], "timeToday": [0, 0],
+    })
+}
+
+/// Anki's default deck options
+fn deck_options(now: u64) -> serde_json::Value {
+    serde_json::json!({ "1": {
+        "id": 1, "name": "Default", "mod": now, "usn": 0, "maxTaken": 60, "autoplay": true, "timer": 0,
+        "replayq": true, "dyn": false,
+        "new": { "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500, "order": 1, "perDay": 20,
+                 "bury": false, "separate": true },
+        "rev": { "perDay": 200, "ease4": 1.3, "fuzz": 0.05, "ivlFct": 1, "maxIvl": 36500, "bury": false,
+                 "hardFactor": 1.2, "minSpace": 1 },
+        "lapse": { "delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0 },
+    }})
+}
+
+/// Anki's `type`, `queue`, `due`, `ivl` and `left` for a card scheduled like this
+fn schedule_columns(state: &ReviewState, position: i64, created: u64) -> (i64, i64, i64, i64, i64) {
+    if state.is_new() {
+        // New cards are due in the order they were added
+        (0, 0, position, 0, 0)
+    } else if state.interval == 0 {
+        // Learning (or relearning after a lapse) - due at a time, with one step to go
+        let kind = if state.lapses > 0 { 3 } else { 1 };
+        (kind, 1, state.due as i64, 0, 1001)
+    } else {
+        // Reviews are due on a day, counted from when the collection was made
+        let day = (state.due.saturating_sub(created) / DAY) as i64;
+        (2, 2, day, i64::from(state.interval), 0)
+    }
+}
+
+/// Anki's `type` of a review - learning, review or relearning
+const fn review_kind(log: &ReviewLog) -> i64 {
+    match (log.last_interval, log.interval) {
+        (0, 0) => 0,
+        (_, 0) => 2,
+        _ => 1,
+    }
+}
+
+/// Writes sets to an Anki package, one deck per set, with the pictures and sounds they use from `media_dir`.
+/// Returns how many of those couldn't be read, and were left out.
+pub fn export(sets: &[FlashcardSet], path: &Path, media_dir: &Path) -> io::Result<usize> {
+    info!("Exporting {} sets to Anki package {}", sets.len(), path.display());
+    let collection = tempfile::NamedTempFile::new()?;
+    {
+        let mut connection = Connection::open(collection.path()).map_err(sql_error)?;
+        write_collection(&mut connection, sets).map_err(sql_error)?;
+    }
+    let mut zip = zip::ZipWriter::new(File::create(path)?);
+    let options = zip::write::FileOptions::default();
+    zip.start_file("collection.anki2", options).map_err(|e| invalid(e.to_string()))?;
+    zip.write_all(&std::fs::read(collection.path())?)?;
+    // Pictures and sounds are numbered in the package, with a list of their names
+    let mut names: Vec<&str> = sets
+        .iter()
+        .flat_map(|s| &s.flashcards)
+        .flat_map(|c| c.front.media().chain(c.back.media()))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    let mut numbered = serde_json::Map::new();
+    let mut left_out = 0;
+    for name in names {
+        match std::fs::read(media_dir.join(name)) {
+            Ok(bytes) => {
+                let number = numbered.len().to_string();
+                zip.start_file(number.as_str(), options).map_err(|e| invalid(e.to_string()))?;
+                zip.write_all(&bytes)?;
+                numbered.insert(number, name.into());
+            }
+            Err(e) => {
+                warn!("Could not add {} to the package: {}", name, e);
+                left_out += 1;
+            }
+        }
+    }
+    zip.start_file("media", options).map_err(|e| invalid(e.to_string()))?;
+    zip.write_all(serde_json::Value::Object(numbered).to_string().as_bytes())?;
+    zip.finish().map_err(|e| invalid(e.to_string()))?;
+    Ok(left_out)
+}
+
+/// Fills an empty database with the sets as an Anki collection
+fn write_collection(connection: &mut Connection, sets: &[FlashcardSet]) -> rusqlite::Result<()> {
+    let now = crate::schedule::now();
+    // Anki ids are times in milliseconds - count up from now so they're all different
+    let mut next_id = now as i64 * 1000;
+    let mut new_id = || {
+        next_id += 1;
+        next_id
+    };
+    // The collection must be made before anything is due, so due days aren't negative
+    let earliest_due = sets
+        .iter()
+        .flat_map(|s| &s.flashcards)
+        .flat_map(|c| [&c.review, &c.reverse_review])
+        .filter(|r| r.interval > 0)
+        .map(|r| r.due)
+        .min()
+        .unwrap_or(now);
+    let created = earliest_due.min(now) / DAY * DAY;
+
+    // A note type for each way a set can be studied
+    let mut note_types = serde_json::Map::new();
+    let note_type_ids: Vec<(StudyDirection, i64)> = StudyDirection::ALL
+        .iter()
+        .map(|direction| {
+            let id = new_id();
+            let name = format!("Magistrax ({})", direction.label());
+            note_types.insert(id.to_string(), note_type(id, &name, direction.directions(), now));
+            (*direction, id)
+        })
+        .collect();
+    let mut decks = serde_json::Map::new();
+    decks.insert(DEFAULT_DECK.to_string(), deck(DEFAULT_DECK, "Default", now));
+    let deck_ids: Vec<i64> = sets
+        .iter()
+        .map(|set| {
+            let id = new_id();
+            decks.insert(id.to_string(), deck(id, &set.name, now));
+            id
+        })
+        .collect();
+    let conf = serde_json::json!({
+        "nextPos": sets.iter().map(|s| s.flashcards.len()).sum::<usize>() + 1,
+        "estTimes": true, "activeDecks": [DEFAULT_DECK], "sortType": "noteFld", "timeLim": 0,
+        "sortBackwards": false, "addToCur": true, "curDeck": DEFAULT_DECK, "newBury": true, "newSpread": 0,
+        "dueCounts": true, "curModel": null, "collapseTime": 1200,
+    });
+
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(SCHEMA)?;
+    transaction.execute(
+        "INSERT INTO col VALUES (1, ?, ?, ?, 11, 0, 0, 0, ?, ?, ?, ?, '{}')",
+        params![
+            created as i64,
+            now as i64 * 1000,
+            now as i64 * 1000,
+            conf.to_string(),
+            serde_json::Value::Object(note_types).to_string(),
+            serde_json::Value::Object(decks).to_string(),
+            deck_options(now).to_string(),
+        ],
+    )?;
+    let mut position = 0;
+    let mut revlog_ids = std::collections::HashSet::new();
+    for (set, deck_id) in sets.iter().zip(deck_ids) {
+        let note_type = note_type_ids
+            .iter()
+            .find(|(direction, _)| *direction == set.direction)
+            .map_or(0, |(_, id)| *id);
+        for card in &set.flashcards {
+            position += 1;
+            let note_id = new_id();
+            let fields = format!("{}{}{}", to_field(&card.front), FIELD_SEPARATOR, to_field(&card.back));
+            transaction.execute(
+                // The checksum is only used to spot duplicates - Anki's "Check Database" fills it in
+                "INSERT INTO notes VALUES (?, ?, ?, ?, 0, '', ?, ?, 0, 0, '')",
+                params![
+                    note_id,
+                    format!("magistrax-{}-{}-{}", set.id(), card.id(), note_id),
+                    note_type,
+                    now as i64,
+                    fields,
+                    card.front.text,
+                ],
+            )?;
+            for (ord, direction) in set.direction.directions().iter().enumerate() {
+                let card_id = new_id();
+                let state = card.review_state(*direction);
+                let (kind, queue, due, interval, left) = schedule_columns(state, position, created);
+                transaction.execute(
+                    "INSERT INTO cards VALUES (?, ?, ?, ?, ?, 0, ?, ?, ?, ?, ?, ?, ?, ?, 0, 0, 0, '')",
+                    params![
+                        card_id,
+                        note_id,
+                        deck_id,
+                        ord as i64,
+                        now as i64,
+                        kind,
+                        queue,
+                        due,
+                        interval,
+                        if state.is_new() { 0 } else { i64::from(state.ease) },
+                        i64::from(state.reviews),
+                        i64::from(state.lapses),
+                        left,
+                    ],
+                )?;
+                for log in card.history.iter().filter(|log| log.direction == *direction) {
+                    // Review ids are times in milliseconds, and have to be unique
+                    let mut id = log.time as i64 * 1000;
+                    while !revlog_ids.insert(id) {
+                        id += 1;
+                    }
+                    let interval = |days: u32| if days == 0 { LEARNING_STEP } else { i64::from(days) };
+                    let ease = Grade::ALL.iter().position(|g| *g == log.grade).unwrap_or(2) as i64 + 1;
+                    transaction.execute(
+                        "INSERT INTO revlog VALUES (?, ?, 0, ?, ?, ?, ?, 0, ?)",
+                        params![
+                            id,
+                            card_id,
+                            ease,
+                            interval(log.interval),
+                            interval(log.last_interval),
+                            i64::from(log.ease),
+                            review_kind(log),
+                        ],
+                    )?;
+                }
+            }
+        }
+    }
+    transaction.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::UserData;
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(
+            decode_entities("a &amp; b &lt;c&gt; &#233;&#xE9;&nbsp;&unknown; & x"),
+            "a & b <c> éé &unknown; & x"
+        );
+    }
+
+    #[test]
+    fn turns_html_into_text() {
+        assert_eq!(
+            html_to_text("Line one<br>Line <b>two</b><div>three</div>[sound:a.mp3]<img src=\"b.png\">&amp; more"),
+            "Line one\nLine two\nthree\n& more"
+        );
+    }
+
+    #[test]
+    fn finds_media_in_fields() {
+        let html = "<IMG class=\"x\" data-src=\"no.png\" src='a%20b.png'>[sound:s&amp;t.mp3]<img src=c.gif/>";
+        assert_eq!(
+            field_media(html),
+            vec![
+                ("a%20b.png".into(), MediaKind::Image),
+                ("c.gif".into(), MediaKind::Image),
+                ("s&t.mp3".into(), MediaKind::Audio),
+            ]
+        );
+        assert_eq!(percent_decode("a%20b%zz.png"), "a b%zz.png");
+    }
+
+    #[test]
+    fn makes_a_card_per_cloze_gap() {
+        let fields = format!("{{{{c1::Paris}}}} is the capital of {{{{c2::France::country}}}}{}Extra", FIELD_SEPARATOR);
+        let (front, back) = cloze_sides(&fields, 1);
+        assert_eq!(front, "[...] is the capital of France");
+        assert_eq!(back, "Paris is the capital of France\nExtra");
+        assert_eq!(cloze_sides(&fields, 2).0, "Paris is the capital of [country]");
+    }
+
+    #[test]
+    fn converts_scheduling() {
+        let mut card = Card {
+            id: 1,
+            note: 1,
+            deck: 1,
+            ord: 0,
+            kind: 0,
+            due: 10,
+            interval: 5,
+            factor: 2100,
+            reps: 7,
+            lapses: 2,
+        };
+        let created = 1_600_000_000;
+        assert_eq!(review_state(&card, created), ReviewState::default());
+        card.kind = 2;
+        let state = review_state(&card, created);
+        assert_eq!((state.ease, state.interval, state.reviews, state.lapses), (2100, 5, 7, 2));
+        assert_eq!(state.due, created as u64 + 10 * DAY);
+        // Learning cards are due at a time
+        card.due = 1_700_000_000;
+        assert_eq!(review_state(&card, created).due, 1_700_000_000);
+    }
+
+    #[test]
+    fn reads_each_cloze_card_with_its_own_scheduling_and_media() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection
+            .execute(
+                "INSERT INTO col VALUES (1, 0, 0, 0, 11, 0, 0, 0, '{}', ?, ?, '{}', '{}')",
+                [r#"{"5": {"type": 1, "tmpls": [{}]}}"#, r#"{"1": {"name": "Geography"}}"#],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO notes VALUES (1, 'g', 5, 0, 0, '', ?, '', 0, 0, '')",
+                ["{{c1::Paris}} is in {{c2::France}}<img src=\"map.png\"><img src=\"gone.png\">"],
+            )
+            .unwrap();
+        for (id, ord, interval) in [(10, 0, 3), (11, 1, 30)] {
+            connection
+                .execute(
+                    "INSERT INTO cards VALUES (?, 1, 1, ?, 0, 0, 2, 2, 5, ?, 2500, 4, 0, 0, 0, 0, 0, '')",
+                    params![id, ord, interval],
+                )
+                .unwrap();
+        }
+        let mut media = |name: &str, kind| (name == "map.png" && kind == MediaKind::Image).then(|| "stored.png".to_owned());
+        let package = read_collection(&connection, &mut media).unwrap();
+
+        assert_eq!(package.skipped_media, 1);
+        let cards = &package.sets[0].flashcards;
+        assert_eq!(cards.len(), 2);
+        assert_eq!(cards[0].front.text, "[...] is in France");
+        assert_eq!(cards[0].review.interval, 3);
+        assert_eq!(cards[1].front.text, "Paris is in [...]");
+        assert_eq!(cards[1].review.interval, 30);
+        assert_eq!(cards[1].front.images, vec!["stored.png".to_owned()]);
+        assert_eq!(cards[1].back.images, vec!["stored.png".to_owned()]);
+    }
+
+    #[test]
+    fn round_trips_through_a_collection() {
+        let mut data = UserData::default();
+        let start = 1_700_000_000;
+        data.sets[0].flashcards[0].record_review(Direction::Forward, Grade::Good, start);
+        data.sets[0].flashcards[0].record_review(Direction::Forward, Grade::Easy, start + DAY);
+        data.sets[0].flashcards[1].front.images = vec![format!("{}.png", "a".repeat(64))];
+        data.sets[0].flashcards[1].back.audio = vec![format!("{}.mp3", "b".repeat(64))];
+        data.sets[1].direction = StudyDirection::Both;
+        data.sets[1].flashcards[1].record_review(Direction::Reverse, Grade::Again, start);
+        // A set only studied backwards is a note type whose one template is the reversed card
+        let mut spanish = FlashcardSet::new("Spanish".into());
+        spanish.add(RichText::plaintext("Cat".into()), RichText::plaintext("Gato".into()));
+        spanish.direction = StudyDirection::Reverse;
+        spanish.flashcards[0].record_review(Direction::Reverse, Grade::Good, start);
+        data.add_set(spanish);
+        let mut connection = Connection::open_in_memory().unwrap();
+        write_collection(&mut connection, &data.sets).unwrap();
+        // The media are already in the store, under the same names
+        let package = read_collection(&connection, &mut |name, _| Some(name.to_owned())).unwrap();
+
+        assert_eq!(package.skipped_media, 0);
+        assert_eq!(package.sets.len(), data.sets.len());
+        for (exported, imported) in data.sets.iter().zip(&package.sets) {
+            assert_eq!(imported.name, exported.name);
+            assert_eq!(imported.direction, exported.direction);
+            assert_eq!(imported.flashcards.len(), exported.flashcards.len());
+            for (exported, imported) in exported.flashcards.iter().zip(&imported.flashcards) {
+                assert_eq!(imported.front, exported.front);
+                assert_eq!(imported.back, exported.back);
+                assert_eq!(imported.history, exported.history);
+                for direction in [Direction::Forward, Direction::Reverse] {
+                    let (exported, imported) = (exported.review_state(direction), imported.review_state(direction));
+                    assert_eq!(imported.interval, exported.interval);
+                    assert_eq!(imported.ease, exported.ease);
+                    assert_eq!(imported.reviews, exported.reviews);
+                    assert_eq!(imported.lapses, exported.lapses);
+                    // Reviews are due on a day rather than at a time
+                    let due = if exported.interval > 0 { exported.due / DAY * DAY } else { exported.due };
+                    assert_eq!(imported.due, due);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Checking typed answers against the back of a flashcard, and generating multiple-choice options
+use std::cmp::Reverse;
+
+use rand::{prelude::SliceRandom, Rng};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::{
+    data::FlashcardSet,
+    schedule::{Grade, Prompt},
+};
+
+/// Normalises an answer for comparison - ignores case, punctuation and repeated whitespace,
+/// and optionally accents
+pub fn normalise(text: &str, fold_accents: bool) -> String {
+    let text: String = if fold_accents {
+        text.nfd().filter(|c| !is_combining_mark(*c)).collect()
+    } else {
+        text.nfc().collect()
+    };
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace() || is_combining_mark(*c))
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether an answer has text that can be typed or picked from a list -
+/// a side with only pictures or sounds doesn't
+pub fn answerable(text: &str) -> bool {
+    !normalise(text, false).is_empty()
+}
+
+/// How a part of the typed answer compares to the expected answer
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DiffKind {
+    /// In both answers
+    Same,
+    /// In the expected answer but not typed
+    Missing,
+    /// Typed but not in the expected answer
+    Extra,
+}
+
+/// A run of characters with the same [`DiffKind`]
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DiffSpan {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+/// How close a typed answer was
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Verdict {
+    Correct,
+    /// A few characters off - probably a typo
+    Close,
+    Wrong,
+}
+impl Verdict {
+    /// The grade a card gets for this verdict
+    pub const fn grade(self) -> Grade {
+        match self {
+            Self::Correct => Grade::Good,
+            Self::Close => Grade::Hard,
+            Self::Wrong => Grade::Again,
+        }
+    }
+}
+
+/// The result of checking a typed answer
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AnswerScore {
+    /// Edit distance between the normalised answers
+    pub distance: usize,
+    pub verdict: Verdict,
+    /// Character-level diff between the normalised answers
+    pub diff: Vec<DiffSpan>,
+}
+
+/// Shortest answer, in characters, that's allowed a typo
+const MIN_TYPO_LENGTH: usize = 4;
+
+/// Checks `given` against `expected`
+pub fn score(expected: &str, given: &str, fold_accents: bool) -> AnswerScore {
+    let expected: Vec<char> = normalise(expected, fold_accents).chars().collect();
+    let given: Vec<char> = normalise(given, fold_accents).chars().collect();
+    let table = distance_table(&expected, &given);
+    let distance = table[expected.len()][given.len()];
+    // Allow roughly one typo every five characters (not bytes) - but none in short answers like numbers,
+    // where one wrong character makes a different answer
+    let typos = if expected.len() < MIN_TYPO_LENGTH { 0 } else { (expected.len() / 5).max(1) };
+    let verdict = if distance == 0 {
+        Verdict::Correct
+    } else if distance <= typos && !given.is_empty() {
+        Verdict::Close
+    } else {
+        Verdict::Wrong
+    };
+    AnswerScore {
+        distance,
+        verdict,
+        diff: diff(&table, &expected, &given),
+    }
+}
+
+/// Levenshtein distances between all prefixes of `a` and `b`
+fn distance_table(a: &[char], b: &[char]) -> Vec<Vec<usize>> {
+    let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in table.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in table[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = table[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            table[i][j] = substitution
+                .min(table[i - 1][j] + 1)
+                .min(table[i][j - 1] + 1);
+        }
+    }
+    table
+}
+
+/// Walks back through the distance table to find which characters differ
+fn diff(table: &[Vec<usize>], expected: &[char], given: &[char]) -> Vec<DiffSpan> {
+    let (mut i, mut j) = (expected.len(), given.len());
+    let mut chars = vec![];
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && expected[i - 1] == given[j - 1] && table[i][j] == table[i - 1][j - 1] {
+            chars.push((DiffKind::Same, expected[i - 1]));
+            i -= 1;
+            j -= 1;
+        } else if j > 0 && (i == 0 || table[i][j] == table[i][j - 1] + 1) {
+            chars.push((DiffKind::Extra, given[j - 1]));
+            j -= 1;
+        } else if i > 0 && j > 0 && table[i][j] == table[i - 1][j - 1] + 1 {
+            // Substitution - show what was typed, then what was expected
+            chars.push((DiffKind::Missing, expected[i - 1]));
+            chars.push((DiffKind::Extra, given[j - 1]));
+            i -= 1;
+            j -= 1;
+        } else {
+            chars.push((DiffKind::Missing, expected[i - 1]));
+            i -= 1;
+        }
+    }
+    let mut spans: Vec<DiffSpan> = vec![];
+    for (kind, c) in chars.into_iter().rev() {
+        match spans.last_mut() {
+            Some(span) if span.kind == kind => span.text.push(c),
+            _ => spans.push(DiffSpan {
+                kind,
+                text: c.into(),
+            }),
+        }
+    }
+    spans
+}
+
+/// Picks up to `count` answer options for a prompt - the card's own answer, and distractors
+/// taken from the same side of the other cards in the set.
+/// With `similar`, distractors of a similar length and with the same start are preferred.
+/// Returns the options and the index of the correct one.
+pub fn choices(
+    set: &FlashcardSet,
+    prompt: Prompt,
+    count: usize,
+    similar: bool,
+    rng: &mut impl Rng,
+) -> (Vec<String>, usize) {
+    let correct = &set[prompt.card].answer(prompt.direction).text;
+    let correct_normalised = normalise(correct, false);
+    // Each option with its normalised form, so ones that only differ in case or punctuation count as the same
+    let mut others: Vec<(String, &str)> = set
+        .flashcards
+        .iter()
+        .filter(|f| f.id() != prompt.card)
+        .map(|f| f.answer(prompt.direction).text.as_str())
+        .map(|t| (normalise(t, false), t))
+        // Sides with only pictures or sounds would be blank options
+        .filter(|(normalised, _)| !normalised.is_empty() && *normalised != correct_normalised)
+        .collect();
+    others.sort_unstable();
+    others.dedup_by(|(a, _), (b, _)| a == b);
+    others.shuffle(rng);
+    if similar {
+        // Stable sort, so equally similar options stay shuffled
+        others.sort_by_key(|(normalised, _)| similarity_key(&correct_normalised, normalised));
+    }
+    let mut options: Vec<String> = others
+        .into_iter()
+        .take(count.saturating_sub(1))
+        .map(|(_, t)| t.to_owned())
+        .collect();
+    let index = rng.gen_range(0..=options.len());
+    options.insert(index, correct.clone());
+    (options, index)
+}
+
+/// Sort key putting options that look more like `correct` first
+fn similarity_key(correct: &str, other: &str) -> (Reverse<usize>, usize) {
+    let prefix = correct
+        .chars()
+        .zip(other.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let length_difference = correct.chars().count().abs_diff(other.chars().count());
+    (Reverse(prefix), length_difference)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{data::RichText, schedule::Direction};
+
+    #[test]
+    fn normalises_answers() {
+        assert_eq!(normalise("  Hello,   WORLD! ", false), "hello world");
+        assert_eq!(normalise("Café", false), "café");
+        assert_eq!(normalise("Café", true), "cafe");
+        // Composed and decomposed accents are the same
+        assert_eq!(normalise("cafe\u{301}", false), normalise("caf\u{e9}", false));
+    }
+
+    #[test]
+    fn measures_edit_distance() {
+        let distance = |a: &str, b: &str| {
+            let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+            distance_table(&a, &b)[a.len()][b.len()]
+        };
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("same", "same"), 0);
+    }
+
+    #[test]
+    fn scores_and_diffs_answers() {
+        let exact = score("J'habite", "j'HABITE!", false);
+        assert_eq!(exact.verdict, Verdict::Correct);
+        assert_eq!(exact.diff, vec![DiffSpan { kind: DiffKind::Same, text: "jhabite".into() }]);
+
+        let typo = score("Kakerlaken", "kakerlakn", false);
+        assert_eq!(typo.verdict, Verdict::Close);
+        assert_eq!(
+            typo.diff,
+            vec![
+                DiffSpan { kind: DiffKind::Same, text: "kakerlak".into() },
+                DiffSpan { kind: DiffKind::Missing, text: "e".into() },
+                DiffSpan { kind: DiffKind::Same, text: "n".into() },
+            ]
+        );
+        assert_eq!(score("Essen", "trinken", false).verdict, Verdict::Wrong);
+        assert_eq!(score("é", "", false).verdict, Verdict::Wrong);
+    }
+
+    #[test]
+    fn short_answers_allow_no_typos() {
+        assert_eq!(score("5", "6", false).verdict, Verdict::Wrong);
+        assert_eq!(score("5", "5", false).verdict, Verdict::Correct);
+        assert_eq!(score("car", "cat", false).verdict, Verdict::Wrong);
+        assert_eq!(score("cart", "carts", false).verdict, Verdict::Close);
+        // Counted in characters - "été" is three, though it's five bytes
+        assert_eq!(score("été", "éta", false).verdict, Verdict::Wrong);
+        assert_eq!(score("Äpfel", "Apfel", false).verdict, Verdict::Close);
+    }
+
+    #[test]
+    fn picks_distinct_choices() {
+        let mut set = FlashcardSet::new("Colours".into());
+        for back in ["Rouge", "rouge!", "Bleu", "ROUGE", "Vert", "bleu", "", "Jaune", "?"] {
+            set.add(RichText::plaintext("?".into()), RichText::plaintext(back.into()));
+        }
+        let prompt = Prompt {
+            card: set.flashcards[2].id(),
+            direction: Direction::Forward,
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..50 {
+            let (options, index) = choices(&set, prompt, 4, false, &mut rng);
+            assert_eq!(options.len(), 4);
+            assert_eq!(options[index], "Bleu");
+            let mut normalised: Vec<_> = options.iter().map(|o| normalise(o, false)).collect();
+            normalised.sort();
+            normalised.dedup();
+            assert_eq!(normalised.len(), 4, "{:?}", options);
+        }
+        // Fewer options when there aren't enough different answers
+        let (options, _) = choices(&set, prompt, 10, true, &mut rng);
+        assert_eq!(options.len(), 4);
+    }
+}
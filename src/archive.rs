@@ -0,0 +1,377 @@
+//! Backing up everything - sets, review history, settings and media - into one file, and restoring from it
+use std::{
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use dioxus::{
+    fermi::{use_read, use_set},
+    prelude::*,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    data::UserData, flashcards::DELETED_SET, media, note_input::ACTIVE_SET, persist, schedule, storage,
+    CurrentPage, CURRENT_PAGE, USER_DATA,
+};
+
+/// Version of the archive layout written by this version of the app.
+/// The user data inside has a version of its own, see [`crate::migrate`].
+const ARCHIVE_VERSION: u64 = 1;
+/// Extension of archive files
+const EXTENSION: &str = "magistrax";
+const MANIFEST: &str = "manifest.json";
+const USER_DATA_FILE: &str = "user-data.json";
+/// Folder in the archive the media files are kept in
+const MEDIA_PREFIX: &str = "media/";
+
+/// Describes an archive - written first, so it can be checked before reading the rest
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    archive_version: u64,
+    /// When the archive was made, in seconds since the unix epoch
+    created: u64,
+}
+
+/// What was read from an archive
+pub struct Archive {
+    pub data: UserData,
+    /// Names and contents of the media files
+    pub media: Vec<(String, Vec<u8>)>,
+}
+
+/// How a restored archive is combined with the data that's already there
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RestoreMode {
+    /// Add the archive's sets after the existing ones, renaming any whose names are taken
+    Merge,
+    /// Throw the existing data away and use the archive's instead
+    Replace,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    match e {
+        zip::result::ZipError::Io(e) => e,
+        e => invalid(e.to_string()),
+    }
+}
+
+/// Writes `data`, and the files in `media_dir`, to an archive at `path`
+pub fn write(data: &UserData, media_dir: &Path, path: &Path) -> io::Result<()> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    let compressed = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(MANIFEST, compressed).map_err(zip_error)?;
+    let manifest = Manifest {
+        archive_version: ARCHIVE_VERSION,
+        created: schedule::now(),
+    };
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    zip.start_file(USER_DATA_FILE, compressed).map_err(zip_error)?;
+    serde_json::to_writer(&mut zip, data)?;
+
+    let entries = match fs::read_dir(media_dir) {
+        Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    // Images and sounds are compressed already
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_file() || !media::valid_name(&name) {
+            continue;
+        }
+        zip.start_file(format!("{}{}", MEDIA_PREFIX, name), stored).map_err(zip_error)?;
+        io::copy(&mut File::open(entry.path())?, &mut zip)?;
+    }
+    let bytes = zip.finish().map_err(zip_error)?.into_inner();
+    info!("Writing backup archive {}", path.display());
+    persist::write_atomic(path, &bytes)
+}
+
+/// Reads an archive, upgrading the data in it if it was made by an older version
+pub fn read(path: &Path) -> io::Result<Archive> {
+    info!("Reading backup archive {}", path.display());
+    let not_backup = || invalid("this isn't a Magistrax backup");
+    let mut zip = ZipArchive::new(File::open(path)?).map_err(|_| not_backup())?;
+    let manifest: Manifest = serde_json::from_reader(zip.by_name(MANIFEST).map_err(|_| not_backup())?)?;
+    if manifest.archive_version > ARCHIVE_VERSION {
+        return Err(invalid(format!(
+            "this backup was made by a newer version of the app (archive version {})",
+            manifest.archive_version
+        )));
+    }
+    let data = UserData::from_json(serde_json::from_reader(
+        zip.by_name(USER_DATA_FILE).map_err(zip_error)?,
+    )?)?;
+    let mut media = vec![];
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(zip_error)?;
+        let name = match entry.name().strip_prefix(MEDIA_PREFIX) {
+            Some(name) if media::valid_name(name) => name.to_owned(),
+            Some(name) => {
+                warn!("Skipping media file with an unsafe name {:?}", name);
+                continue;
+            }
+            None => continue,
+        };
+        let mut bytes = vec![];
+        entry.read_to_end(&mut bytes)?;
+        media.push((name, bytes));
+    }
+    Ok(Archive { data, media })
+}
+
+/// Archives the data that's about to be replaced into the backup folder next to `save_path`,
+/// so replacing it by mistake can be undone by restoring that archive
+pub fn archive_before_replacing(data: &UserData, media_dir: &Path, save_path: &Path) -> io::Result<PathBuf> {
+    let dir = persist::backup_dir(save_path);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("before-restore-{}.{}", schedule::now(), EXTENSION));
+    write(data, media_dir, &path)?;
+    Ok(path)
+}
+
+/// Copies an archive's media into `media_dir`, keeping any files that are already there
+pub fn restore_media(media: &[(String, Vec<u8>)], media_dir: &Path) -> io::Result<()> {
+    if media.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(media_dir)?;
+    for (name, bytes) in media {
+        let path = media_dir.join(name);
+        if !path.exists() {
+            persist::write_atomic(&path, bytes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Page for backing up all the user's data to one file, and restoring it
+pub fn Backup(cx: Scope) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    let set_page = use_set(&cx, CURRENT_PAGE);
+    let set_deleted = use_set(&cx, DELETED_SET);
+    let active_set = use_read(&cx, ACTIVE_SET);
+    let chosen = use_ref(&cx, || None::<(PathBuf, Archive)>);
+    let (mode, set_mode) = use_state(&cx, || RestoreMode::Merge);
+    let (message, set_message) = use_state(&cx, || None::<String>);
+
+    let back_up = move |_| {
+        let path = match rfd::FileDialog::new()
+            .set_file_name(&format!("Magistrax backup.{}", EXTENSION))
+            .add_filter("Magistrax backup", &[EXTENSION])
+            .save_file()
+        {
+            Some(path) => path,
+            None => return,
+        };
+        let user_data = user_data.borrow();
+        let media_dir = storage::media_dir(user_data.storage().path());
+        set_message(Some(match write(user_data.get(), &media_dir, &path) {
+            Ok(()) => format!("Backed up to {}", path.display()),
+            Err(e) => format!("Couldn't back up your data: {}", e),
+        }));
+    };
+    let choose = move |_| {
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("Magistrax backup", &[EXTENSION])
+            .pick_file()
+        {
+            match read(&path) {
+                Ok(archive) => {
+                    set_message(None);
+                    *chosen.write() = Some((path, archive));
+                }
+                Err(e) => set_message(Some(format!("That backup couldn't be read: {}", e))),
+            }
+        }
+    };
+    let restore = move |_| {
+        let mut user_data = user_data.borrow_mut();
+        let media_dir = storage::media_dir(user_data.storage().path());
+        if chosen.read().is_none() {
+            return;
+        }
+        if *mode == RestoreMode::Replace {
+            match archive_before_replacing(user_data.get(), &media_dir, user_data.storage().path()) {
+                Ok(path) => info!("Kept the data being replaced in {}", path.display()),
+                Err(e) => {
+                    set_message(Some(format!("Your data couldn't be backed up before replacing it: {}", e)));
+                    return;
+                }
+            }
+        }
+        let (_, archive) = match chosen.write().take() {
+            Some(chosen) => chosen,
+            None => return,
+        };
+        if let Err(e) = restore_media(&archive.media, &media_dir) {
+            set_message(Some(format!("The images and sounds couldn't be restored: {}", e)));
+            return;
+        }
+        match mode {
+            RestoreMode::Merge => {
+                let mut renamed = 0;
+                user_data.modify(|data| renamed = data.merge(archive.data));
+                if renamed > 0 {
+                    info!("Renamed {} restored sets whose names were taken", renamed);
+                }
+            }
+            RestoreMode::Replace => {
+                user_data.modify(|data| *data = archive.data);
+                // Forget anything that belonged to the replaced data
+                set_deleted(None);
+                *active_set.borrow_mut() = None;
+            }
+        }
+        set_page(CurrentPage::Flashcards);
+    };
+
+    let chosen_borrow = chosen.read();
+    let summary = chosen_borrow.as_ref().map(|(path, archive)| {
+        let path = path.display().to_string();
+        let set_count = archive.data.sets.len();
+        let card_count: usize = archive.data.sets.iter().map(|s| s.flashcards.len()).sum();
+        let media_count = archive.media.len();
+        rsx!(cx, div {
+            p {"{path}"}
+            p {"{set_count} sets, {card_count} cards and {media_count} images and sounds"}
+            label {
+                "When restoring "
+                select {
+                    oninput: move |e| set_mode(if e.value == "replace" { RestoreMode::Replace } else { RestoreMode::Merge }),
+                    option { value: "merge", selected: "{*mode == RestoreMode::Merge}", "add its sets to yours" }
+                    option { value: "replace", selected: "{*mode == RestoreMode::Replace}", "replace all your data" }
+                }
+            }
+            match mode {
+                RestoreMode::Merge => rsx!(cx, p {"Sets with the same name as one of yours will be renamed."}),
+                RestoreMode::Replace => rsx!(cx, p {
+                    class: "warning",
+                    "Your current sets, history and settings will be replaced by the backup's. A copy of them is kept in the backups folder first."
+                }),
+            }
+            button {
+                "type": "button",
+                onclick: restore,
+                "Restore"
+            }
+        })
+    });
+
+    rsx!(cx, div {
+        class: "center-div",
+        h1 {"Back up and restore"}
+        p {"A backup holds all your sets, their review history, your settings, images and sounds in one file."}
+        button {
+            "type": "button",
+            onclick: back_up,
+            "Back up everything"
+        }
+        button {
+            "type": "button",
+            onclick: choose,
+            "Restore from a backup"
+        }
+        summary
+        message.as_ref().map(|m| rsx!(cx, p { "{m}" }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::schedule::{Direction, Grade};
+
+    fn studied_data() -> UserData {
+        let mut data = UserData::default();
+        data.sets[0].flashcards[0].record_review(Direction::Forward, Grade::Good, 1000);
+        data.study_time = Duration::from_secs(90);
+        data
+    }
+
+    #[test]
+    fn round_trips_sets_history_and_media() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_dir = dir.path().join("media");
+        fs::create_dir(&media_dir).unwrap();
+        let name = media::store(&media_dir, b"not really a png", "png").unwrap();
+        let data = studied_data();
+        let path = dir.path().join("backup.magistrax");
+
+        write(&data, &media_dir, &path).unwrap();
+        let archive = read(&path).unwrap();
+        assert_eq!(
+            serde_json::to_value(&archive.data).unwrap(),
+            serde_json::to_value(&data).unwrap()
+        );
+        assert_eq!(archive.data.sets[0].flashcards[0].history.len(), 1);
+        assert_eq!(archive.media, [(name.clone(), b"not really a png".to_vec())]);
+
+        let restored_dir = dir.path().join("restored");
+        restore_media(&archive.media, &restored_dir).unwrap();
+        assert_eq!(fs::read(restored_dir.join(name)).unwrap(), b"not really a png");
+    }
+
+    #[test]
+    fn rejects_archives_from_newer_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.magistrax");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file(MANIFEST, FileOptions::default()).unwrap();
+        let manifest = Manifest {
+            archive_version: ARCHIVE_VERSION + 1,
+            created: 0,
+        };
+        zip.write_all(&serde_json::to_vec(&manifest).unwrap()).unwrap();
+        zip.start_file(USER_DATA_FILE, FileOptions::default()).unwrap();
+        serde_json::to_writer(&mut zip, &UserData::default()).unwrap();
+        zip.finish().unwrap();
+
+        let error = read(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("newer version"));
+    }
+
+    #[test]
+    fn merging_renames_sets_whose_names_are_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.magistrax");
+        write(&studied_data(), &dir.path().join("media"), &path).unwrap();
+        let archive = read(&path).unwrap();
+
+        let mut data = UserData::default();
+        let before = data.sets.len();
+        let renamed = data.merge(archive.data);
+        assert_eq!(renamed, before);
+        assert_eq!(data.sets.len(), before * 2);
+        let mut names: Vec<&str> = data.sets.iter().map(|s| s.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), before * 2);
+        assert_eq!(data.sets[before].flashcards[0].history.len(), 1);
+        assert_eq!(data.study_time, Duration::from_secs(90));
+    }
+
+    #[test]
+    fn archives_the_data_before_replacing_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("user-data.json");
+        let data = studied_data();
+        let kept = archive_before_replacing(&data, &dir.path().join("media"), &save_path).unwrap();
+        assert!(kept.starts_with(persist::backup_dir(&save_path)));
+        // Not mistaken for a backup of the save file
+        assert!(persist::backups(&save_path).is_empty());
+        assert_eq!(read(&kept).unwrap().data.sets[0].flashcards[0].history.len(), 1);
+    }
+}
@@ -0,0 +1,469 @@
+//! Saves user data on a background thread, a little while after it changes
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+
+use crate::{
+    data::{Flashcard, FlashcardSet, SetId, UserData},
+    storage::Storage,
+};
+
+/// How long to wait for more changes before saving
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+/// Longest a change waits to be saved, even if more keep coming
+const MAX_SAVE_DELAY: Duration = Duration::from_secs(10);
+
+/// Something that changed in the user data
+pub enum Change {
+    /// Anything could have changed
+    All,
+    /// Something other than the sets, e.g. the study time
+    Meta,
+    /// This set was added or changed
+    Set(SetId),
+    /// This card of the set was changed, but not added, removed or moved
+    Card(SetId, u64),
+    /// This set was deleted
+    RemovedSet(SetId),
+}
+
+/// Everything that's changed since the last save
+#[derive(Default)]
+struct Changes {
+    all: bool,
+    meta: bool,
+    sets: Vec<SetId>,
+    cards: Vec<(SetId, u64)>,
+    removed: Vec<SetId>,
+}
+impl Changes {
+    fn add(&mut self, change: Change) {
+        match change {
+            Change::All => self.all = true,
+            Change::Meta => self.meta = true,
+            Change::Set(id) if !self.sets.contains(&id) => self.sets.push(id),
+            Change::Card(set, card) if !self.cards.contains(&(set, card)) => self.cards.push((set, card)),
+            Change::RemovedSet(id) if !self.removed.contains(&id) => self.removed.push(id),
+            Change::Set(_) | Change::Card(..) | Change::RemovedSet(_) => {}
+        }
+    }
+    /// Writes the changed parts of `data`
+    fn save(&self, storage: &mut dyn Storage, data: &UserData) -> std::io::Result<()> {
+        if self.all {
+            return storage.save(data);
+        }
+        for &id in &self.removed {
+            storage.remove_set(data, id)?;
+        }
+        // A set that was changed and then deleted is removed again here, which is harmless
+        for &id in &self.sets {
+            storage.save_set(data, id)?;
+        }
+        // Cards of sets that were saved whole or removed are already taken care of
+        let mut card_sets: Vec<SetId> = vec![];
+        for &(set, _) in &self.cards {
+            if !self.sets.contains(&set) && !self.removed.contains(&set) && !card_sets.contains(&set) {
+                card_sets.push(set);
+            }
+        }
+        for set in card_sets {
+            let cards: Vec<u64> = self.cards.iter().filter(|(s, _)| *s == set).map(|&(_, card)| card).collect();
+            storage.save_cards(data, set, &cards)?;
+        }
+        if self.meta {
+            storage.save_meta(data)?;
+        }
+        Ok(())
+    }
+}
+
+/// A copy of the part of the data a change touched, for the thread to bring its own copy up to date.
+/// Only that part is copied, so changing a card doesn't mean copying every set.
+enum Update {
+    /// Every set
+    Sets(Vec<FlashcardSet>),
+    /// A set, and where it is in the list
+    Set(usize, Box<FlashcardSet>),
+    Card(SetId, Box<Flashcard>),
+    RemovedSet(SetId),
+    /// Nothing in the sets
+    Meta,
+}
+impl Update {
+    fn new(data: &UserData, change: &Change) -> Self {
+        match *change {
+            Change::All => Self::Sets(data.sets.clone()),
+            Change::Meta => Self::Meta,
+            Change::Set(id) => match data.sets.iter().position(|s| s.id() == id) {
+                Some(index) => Self::Set(index, Box::new(data.sets[index].clone())),
+                None => Self::RemovedSet(id),
+            },
+            Change::Card(set, card) => {
+                let card = data.set(set).and_then(|s| s.flashcards.iter().find(|c| c.id() == card));
+                match card {
+                    Some(card) => Self::Card(set, Box::new(card.clone())),
+                    None => Self::Meta,
+                }
+            }
+            Change::RemovedSet(id) => Self::RemovedSet(id),
+        }
+    }
+    fn apply(self, data: &mut UserData) {
+        match self {
+            Self::Sets(sets) => data.sets = sets,
+            Self::Set(index, set) => {
+                data.sets.retain(|s| s.id() != set.id());
+                data.sets.insert(index.min(data.sets.len()), *set);
+            }
+            Self::Card(set, card) => {
+                let old = data.set_mut(set).and_then(|s| s.flashcards.iter_mut().find(|c| c.id() == card.id()));
+                if let Some(old) = old {
+                    *old = *card;
+                }
+            }
+            Self::RemovedSet(id) => data.sets.retain(|s| s.id() != id),
+            Self::Meta => {}
+        }
+    }
+}
+
+enum Message {
+    /// The data has changed. Everything outside the sets comes along every time - it's small,
+    /// and adding a set changes it too.
+    Changed {
+        meta: Box<UserData>,
+        update: Update,
+        change: Change,
+    },
+    /// Save now, and reply when done
+    Flush(Sender<()>),
+}
+
+/// Every running autosave, by id, so they can all be flushed as the app closes
+static RUNNING: Mutex<Vec<(usize, Sender<Message>)>> = Mutex::new(Vec::new());
+/// Id of the next autosave started
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Locks a mutex, carrying on if another thread panicked while holding it
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Asks the thread behind `sender` to save now, waiting until it has
+fn flush_with(sender: &Sender<Message>) {
+    let (done, wait) = mpsc::channel();
+    if sender.send(Message::Flush(done)).is_ok() {
+        let _ = wait.recv();
+    }
+}
+
+/// Saves the queued changes of every running autosave, waiting until they're written.
+/// The window closing ends the process without dropping anything, so this is called then.
+pub fn flush_all() {
+    let senders: Vec<_> = lock(&RUNNING).iter().map(|(_, sender)| sender.clone()).collect();
+    for sender in &senders {
+        flush_with(sender);
+    }
+}
+
+/// Hands user data to a background thread, which saves it once changes stop coming in.
+/// Many changes in a row (like grading a whole study session) become one write.
+pub struct Autosave {
+    id: usize,
+    sender: Option<Sender<Message>>,
+    worker: Option<JoinHandle<()>>,
+    storage: Arc<Mutex<Box<dyn Storage>>>,
+    /// Why the last save failed, if it did
+    error: Arc<Mutex<Option<String>>>,
+}
+impl Autosave {
+    /// Starts saving changes to `data`, which is how it is in `storage` now
+    pub fn start(storage: Box<dyn Storage>, data: &UserData) -> Self {
+        Self::with_delays(storage, data, SAVE_DELAY, MAX_SAVE_DELAY)
+    }
+    /// Starts saving changes once they've stopped for `delay`, or the oldest has waited `max_delay`
+    fn with_delays(storage: Box<dyn Storage>, data: &UserData, delay: Duration, max_delay: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let storage = Arc::new(Mutex::new(storage));
+        let error = Arc::new(Mutex::new(None));
+        let worker = {
+            let storage = storage.clone();
+            let error = error.clone();
+            let data = data.clone();
+            thread::Builder::new()
+                .name("autosave".into())
+                .spawn(move || run(&storage, &receiver, &error, data, (delay, max_delay)))
+                .expect("could not start the autosave thread")
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        lock(&RUNNING).push((id, sender.clone()));
+        Self {
+            id,
+            sender: Some(sender),
+            worker: Some(worker),
+            storage,
+            error,
+        }
+    }
+    /// Queues a change to `data` to be saved
+    pub fn changed(&self, data: &UserData, change: Change) {
+        if let Some(sender) = &self.sender {
+            let message = Message::Changed {
+                meta: Box::new(data.clone_meta()),
+                update: Update::new(data, &change),
+                change,
+            };
+            // The thread only stops when this is dropped
+            let _ = sender.send(message);
+        }
+    }
+    /// Saves any queued changes now, waiting until they're written
+    pub fn flush(&self) {
+        if let Some(sender) = &self.sender {
+            flush_with(sender);
+        }
+    }
+    /// Where the data is stored
+    pub fn storage(&self) -> MutexGuard<'_, Box<dyn Storage>> {
+        lock(&self.storage)
+    }
+    /// Why the last save failed, if it did
+    pub fn error(&self) -> Option<String> {
+        lock(&self.error).clone()
+    }
+}
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        // Closing the channel makes the thread save what's left and stop
+        lock(&RUNNING).retain(|(id, _)| *id != self.id);
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The background thread - keeps its own copy of the data up to date, and saves the changes
+/// once things have been quiet for `delay` (see [`SAVE_DELAY`])
+fn run(
+    storage: &Mutex<Box<dyn Storage>>,
+    receiver: &Receiver<Message>,
+    error: &Mutex<Option<String>>,
+    mut data: UserData,
+    (delay, max_delay): (Duration, Duration),
+) {
+    let mut changes = Changes::default();
+    // When the oldest unsaved change was made
+    let mut waiting_since: Option<Instant> = None;
+    let save = |data: &UserData, changes: &mut Changes, waiting_since: &mut Option<Instant>| {
+        if waiting_since.is_none() {
+            return;
+        }
+        let saved = changes.save(lock(storage).as_mut(), data);
+        match saved {
+            Ok(()) => {
+                info!("Saved user data");
+                *changes = Changes::default();
+                *waiting_since = None;
+                *lock(error) = None;
+            }
+            Err(e) => {
+                error!("Could not save data: {}", e);
+                *lock(error) = Some(e.to_string());
+                // Keep the changes and try again later
+                *waiting_since = Some(Instant::now());
+            }
+        }
+    };
+    loop {
+        let message = match waiting_since {
+            None => receiver.recv().ok(),
+            Some(since) => match receiver.recv_timeout(delay.min(max_delay.saturating_sub(since.elapsed()))) {
+                Ok(message) => Some(message),
+                Err(RecvTimeoutError::Timeout) => {
+                    save(&data, &mut changes, &mut waiting_since);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => None,
+            },
+        };
+        match message {
+            Some(Message::Changed { meta, update, change }) => {
+                data.set_meta(*meta);
+                update.apply(&mut data);
+                changes.add(change);
+                waiting_since.get_or_insert_with(Instant::now);
+            }
+            Some(Message::Flush(done)) => {
+                save(&data, &mut changes, &mut waiting_since);
+                let _ = done.send(());
+            }
+            None => {
+                save(&data, &mut changes, &mut waiting_since);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        path::{Path, PathBuf},
+        sync::atomic::AtomicBool,
+    };
+
+    use super::*;
+    use crate::{
+        data::{FlashcardSet, LoadFailure},
+        schedule::{Direction, Grade},
+        storage::JsonStorage,
+    };
+
+    /// Storage that notes what it's asked to write, keeps the data it was last given, and fails while told to
+    #[derive(Default, Clone)]
+    struct Recording {
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        last: Arc<Mutex<String>>,
+        fail: Arc<AtomicBool>,
+        path: PathBuf,
+    }
+    impl Recording {
+        fn write(&self, call: &'static str, data: &UserData) -> io::Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "disk full"));
+            }
+            *lock(&self.last) = serde_json::to_string(data)?;
+            lock(&self.calls).push(call);
+            Ok(())
+        }
+        fn calls(&self) -> Vec<&'static str> {
+            lock(&self.calls).clone()
+        }
+    }
+    impl Storage for Recording {
+        fn load(&mut self) -> Result<UserData, LoadFailure> {
+            Ok(UserData::default())
+        }
+        fn save(&mut self, data: &UserData) -> io::Result<()> {
+            self.write("save", data)
+        }
+        fn save_set(&mut self, data: &UserData, _: SetId) -> io::Result<()> {
+            self.write("save_set", data)
+        }
+        fn save_cards(&mut self, data: &UserData, _: SetId, _: &[u64]) -> io::Result<()> {
+            self.write("save_cards", data)
+        }
+        fn remove_set(&mut self, data: &UserData, _: SetId) -> io::Result<()> {
+            self.write("remove_set", data)
+        }
+        fn save_meta(&mut self, data: &UserData) -> io::Result<()> {
+            self.write("save_meta", data)
+        }
+        fn path(&self) -> &Path {
+            &self.path
+        }
+        fn read_backup(&self, _: &Path) -> io::Result<UserData> {
+            Err(io::ErrorKind::NotFound.into())
+        }
+    }
+
+    #[test]
+    fn saves_bursts_of_changes_at_once() {
+        let storage = Recording::default();
+        let mut data = UserData::default();
+        let delay = Duration::from_millis(100);
+        let autosave = Autosave::with_delays(Box::new(storage.clone()), &data, delay, Duration::from_secs(5));
+        let set = data.sets[0].id();
+        for i in 0..20 {
+            let card = &mut data.set_mut(set).unwrap().flashcards[i % 2];
+            card.record_review(Direction::Forward, Grade::Good, 1000 + i as u64);
+            let card = card.id();
+            autosave.changed(&data, Change::Card(set, card));
+        }
+        data.set_mut(set).unwrap().name = "Renamed".into();
+        autosave.changed(&data, Change::Set(set));
+        let removed = data.sets[1].id();
+        data.remove_set(removed);
+        autosave.changed(&data, Change::RemovedSet(removed));
+        data.study_time += Duration::from_secs(60);
+        autosave.changed(&data, Change::Meta);
+
+        let start = Instant::now();
+        while storage.calls().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(delay * 3);
+        // One write for each part that changed - the cards went with their set
+        assert_eq!(storage.calls(), ["remove_set", "save_set", "save_meta"]);
+        // The thread's copy ended up just like the data
+        assert_eq!(*lock(&storage.last), serde_json::to_string(&data).unwrap());
+    }
+
+    #[test]
+    fn saves_a_steady_stream_of_changes_every_so_often() {
+        let storage = Recording::default();
+        let mut data = UserData::default();
+        let max_delay = Duration::from_millis(300);
+        let autosave = Autosave::with_delays(Box::new(storage.clone()), &data, Duration::from_millis(100), max_delay);
+        let start = Instant::now();
+        while start.elapsed() < max_delay * 3 {
+            data.study_time += Duration::from_secs(1);
+            autosave.changed(&data, Change::Meta);
+            thread::sleep(Duration::from_millis(20));
+        }
+        // Changes never stopped for long enough, but some were saved anyway
+        assert!(storage.calls().len() >= 2);
+        autosave.flush();
+        assert_eq!(*lock(&storage.last), serde_json::to_string(&data).unwrap());
+    }
+
+    #[test]
+    fn reports_failed_saves_until_one_works() {
+        let storage = Recording::default();
+        let mut data = UserData::default();
+        let autosave = Autosave::start(Box::new(storage.clone()), &data);
+        assert_eq!(autosave.error(), None);
+        storage.fail.store(true, Ordering::SeqCst);
+        data.autoplay_audio = true;
+        autosave.changed(&data, Change::Meta);
+        autosave.flush();
+        assert_eq!(autosave.error().as_deref(), Some("disk full"));
+        assert!(storage.calls().is_empty());
+
+        // The change is kept and tried again
+        storage.fail.store(false, Ordering::SeqCst);
+        autosave.flush();
+        assert_eq!(autosave.error(), None);
+        assert_eq!(storage.calls(), ["save_meta"]);
+        assert_eq!(*lock(&storage.last), serde_json::to_string(&data).unwrap());
+    }
+
+    #[test]
+    fn flushes_every_autosave() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        let mut data = UserData::empty();
+        let autosave = Autosave::start(Box::new(JsonStorage::new(path.clone())), &data);
+        data.add_set(FlashcardSet::new("Graded just before closing".into()));
+        autosave.changed(&data, Change::All);
+
+        flush_all();
+        let saved = JsonStorage::new(path).load().unwrap();
+        assert_eq!(saved.sets.len(), 1);
+        assert_eq!(saved.sets[0].name, "Graded just before closing");
+        // A stopped autosave isn't flushed any more
+        let id = autosave.id;
+        drop(autosave);
+        assert!(lock(&RUNNING).iter().all(|(running, _)| *running != id));
+    }
+}
@@ -1,20 +1,43 @@
 //! Contains core data structure types used in the database
 use std::{
+    fmt,
     fs::{self, File},
-    io,
+    io::{self, BufReader},
     ops::{Index, IndexMut},
+    path::{Path, PathBuf},
+    sync::MutexGuard,
 };
 use std::{time::Duration};
 
-use log::{error, info};
-use platform_dirs::AppDirs;
-use serde::{Deserialize, Serialize};
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 
-/// Rich text - user inputted text with colour (for now).
+use crate::{
+    autosave::{Autosave, Change},
+    media, migrate,
+    rich_text::Document,
+    schedule::{Direction, Grade, ReviewLog, ReviewState, StudyDirection},
+    storage::Storage,
+};
+
+/// Rich text - user inputted text with a colour, and optionally formatting (see [`rich_text`]).
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct RichText {
+    /// The text without its formatting - older versions of the app only read this and `color`
     pub text: String,
-    pub color: String,
+    /// Colour of the text, unless the formatting gives part of it another
+    #[serde(deserialize_with = "color_or_black")]
+    pub color: Color,
+    /// Formatting, if there is any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc: Option<Document>,
+    /// Names of the images shown with the text, in the media store (see [`media`])
+    #[serde(default, deserialize_with = "media_names", skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    /// Names of the sounds played with the text, in the media store
+    #[serde(default, deserialize_with = "media_names", skip_serializing_if = "Vec::is_empty")]
+    pub audio: Vec<String>,
 }
 impl RichText {
     /// An empty black text
@@ -25,9 +48,150 @@ impl RichText {
     pub fn plaintext(text: String) -> Self {
         Self {
             text,
-            color: "#000000".into(),
+            color: Color::black(),
+            doc: None,
+            images: vec![],
+            audio: vec![],
+        }
+    }
+    /// Whether there's no text, images or sounds
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.images.is_empty() && self.audio.is_empty()
+    }
+    /// Names of the media files this text uses
+    pub fn media(&self) -> impl Iterator<Item = &str> {
+        self.images.iter().chain(&self.audio).map(String::as_str)
+    }
+}
+
+/// How many recently used colours are remembered
+const MAX_RECENT_COLORS: usize = 6;
+
+/// CSS colour names
+const COLOR_NAMES: [&str; 148] = [
+    "aliceblue", "antiquewhite", "aqua", "aquamarine", "azure", "beige", "bisque", "black", "blanchedalmond",
+    "blue", "blueviolet", "brown", "burlywood", "cadetblue", "chartreuse", "chocolate", "coral", "cornflowerblue",
+    "cornsilk", "crimson", "cyan", "darkblue", "darkcyan", "darkgoldenrod", "darkgray", "darkgreen", "darkgrey",
+    "darkkhaki", "darkmagenta", "darkolivegreen", "darkorange", "darkorchid", "darkred", "darksalmon",
+    "darkseagreen", "darkslateblue", "darkslategray", "darkslategrey", "darkturquoise", "darkviolet", "deeppink",
+    "deepskyblue", "dimgray", "dimgrey", "dodgerblue", "firebrick", "floralwhite", "forestgreen", "fuchsia",
+    "gainsboro", "ghostwhite", "gold", "goldenrod", "gray", "green", "greenyellow", "grey", "honeydew", "hotpink",
+    "indianred", "indigo", "ivory", "khaki", "lavender", "lavenderblush", "lawngreen", "lemonchiffon", "lightblue",
+    "lightcoral", "lightcyan", "lightgoldenrodyellow", "lightgray", "lightgreen", "lightgrey", "lightpink",
+    "lightsalmon", "lightseagreen", "lightskyblue", "lightslategray", "lightslategrey", "lightsteelblue",
+    "lightyellow", "lime", "limegreen", "linen", "magenta", "maroon", "mediumaquamarine", "mediumblue",
+    "mediumorchid", "mediumpurple", "mediumseagreen", "mediumslateblue", "mediumspringgreen", "mediumturquoise",
+    "mediumvioletred", "midnightblue", "mintcream", "mistyrose", "moccasin", "navajowhite", "navy", "oldlace",
+    "olive", "olivedrab", "orange", "orangered", "orchid", "palegoldenrod", "palegreen", "paleturquoise",
+    "palevioletred", "papayawhip", "peachpuff", "peru", "pink", "plum", "powderblue", "purple", "rebeccapurple",
+    "red", "rosybrown", "royalblue", "saddlebrown", "salmon", "sandybrown", "seagreen", "seashell", "sienna",
+    "silver", "skyblue", "slateblue", "slategray", "slategrey", "snow", "springgreen", "steelblue", "tan", "teal",
+    "thistle", "tomato", "turquoise", "violet", "wheat", "white", "whitesmoke", "yellow", "yellowgreen",
+];
+
+/// Whether `color` is written the way [`Color`] allows
+fn is_color(color: &str) -> bool {
+    if let Some(hex) = color.strip_prefix('#') {
+        return matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit());
+    }
+    let arguments = color
+        .strip_prefix("rgba(")
+        .map(|rest| (rest, 4))
+        .or_else(|| color.strip_prefix("rgb(").map(|rest| (rest, 3)));
+    if let Some((rest, count)) = arguments {
+        let numbers: Vec<&str> = match rest.strip_suffix(')') {
+            Some(numbers) => numbers.split(',').map(str::trim).collect(),
+            None => return false,
+        };
+        return numbers.len() == count
+            && numbers.iter().enumerate().all(|(i, n)| match n.parse::<f32>() {
+                // Channels go up to 255, alpha up to 1
+                Ok(value) => value >= 0.0 && value <= if i < 3 { 255.0 } else { 1.0 },
+                Err(_) => false,
+            });
+    }
+    COLOR_NAMES.contains(&color)
+}
+
+/// A colour that's safe to put in a style - `#rgb` or `#rrggbb` (with optional alpha),
+/// `rgb(r, g, b)`, `rgba(r, g, b, a)` or a CSS colour name. Colours are checked as files are read,
+/// so nothing else can reach the page.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(String);
+impl Color {
+    /// The colour of text that hasn't been given one
+    pub fn black() -> Self {
+        Self("#000000".into())
+    }
+    /// Checks a colour, lowercasing it
+    pub fn parse(color: &str) -> Option<Self> {
+        let color = color.trim().to_ascii_lowercase();
+        if is_color(&color) {
+            Some(Self(color))
+        } else {
+            None
         }
     }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+impl TryFrom<String> for Color {
+    type Error = String;
+    fn try_from(color: String) -> Result<Self, String> {
+        Self::parse(&color).ok_or_else(|| format!("{:?} is not a colour", color))
+    }
+}
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.0
+    }
+}
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Reads a colour, falling back to black if it isn't one - a bad colour shouldn't cost the user their card
+fn color_or_black<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    let color = String::deserialize(deserializer)?;
+    Ok(Color::parse(&color).unwrap_or_else(|| {
+        warn!("Ignoring invalid colour {:?}", color);
+        Color::black()
+    }))
+}
+
+/// Reads an optional colour, leaving it out if it isn't one
+pub fn color_or_none<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Color>, D::Error> {
+    let color = Option::<String>::deserialize(deserializer)?;
+    Ok(color.and_then(|color| {
+        let parsed = Color::parse(&color);
+        if parsed.is_none() {
+            warn!("Ignoring invalid colour {:?}", color);
+        }
+        parsed
+    }))
+}
+
+/// Reads a list of colours, leaving out any that aren't
+fn valid_colors<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Color>, D::Error> {
+    let colors = Vec::<String>::deserialize(deserializer)?;
+    Ok(colors.iter().filter_map(|c| Color::parse(c)).collect())
+}
+
+/// Reads the names of media files, leaving out any that can't be in the media store
+fn media_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let mut names = Vec::<String>::deserialize(deserializer)?;
+    names.retain(|name| {
+        let valid = media::valid_name(name);
+        if !valid {
+            warn!("Ignoring invalid media file name {:?}", name);
+        }
+        valid
+    });
+    Ok(names)
 }
 
 /// A flashcard that will be shown to the user, with [`RichText`] on the front and back.
@@ -39,44 +203,149 @@ pub struct Flashcard {
     pub back: RichText,
     /// Flashcard id (used in dioxus keys)
     id: u64,
+    /// Spaced-repetition state of this card, studied front to back
+    #[serde(default)]
+    pub review: ReviewState,
+    /// Spaced-repetition state of this card, studied back to front
+    #[serde(default)]
+    pub reverse_review: ReviewState,
+    /// Every review of this card, oldest first
+    #[serde(default)]
+    pub history: Vec<ReviewLog>,
 }
 impl Flashcard {
     /// Gets the id
     pub const fn id(&self) -> u64 {
         self.id
     }
+    /// The face shown as the prompt in the given direction
+    pub const fn prompt(&self, direction: Direction) -> &RichText {
+        match direction {
+            Direction::Forward => &self.front,
+            Direction::Reverse => &self.back,
+        }
+    }
+    /// The face that has to be recalled in the given direction
+    pub const fn answer(&self, direction: Direction) -> &RichText {
+        match direction {
+            Direction::Forward => &self.back,
+            Direction::Reverse => &self.front,
+        }
+    }
+    /// The review state for studying in the given direction
+    pub const fn review_state(&self, direction: Direction) -> &ReviewState {
+        match direction {
+            Direction::Forward => &self.review,
+            Direction::Reverse => &self.reverse_review,
+        }
+    }
+    /// Reschedules this card after a review at `now` in the given direction, and adds it to the history
+    pub fn record_review(&mut self, direction: Direction, grade: Grade, now: u64) {
+        let state = match direction {
+            Direction::Forward => &mut self.review,
+            Direction::Reverse => &mut self.reverse_review,
+        };
+        let log = state.review(direction, grade, now);
+        self.history.push(log);
+    }
+}
+
+/// Identifies a [`FlashcardSet`] - stays the same when the set is renamed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+#[serde(transparent)]
+pub struct SetId(u64);
+impl SetId {
+    /// Id of a set that hasn't been added to the [`UserData`] yet
+    pub const UNASSIGNED: Self = Self(0);
+}
+impl fmt::Display for SetId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl From<SetId> for u64 {
+    fn from(id: SetId) -> Self {
+        id.0
+    }
+}
+impl std::str::FromStr for SetId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
 }
 
 /// A set of flashcards for easy testing
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FlashcardSet {
+    /// Id of this set, assigned by [`UserData::add_set`]
+    #[serde(default)]
+    id: SetId,
     /// Unique name of this set
     pub name: String,
     /// All the flashcards contained in this set
     pub flashcards: Vec<Flashcard>,
     /// Highest id (for adding cards)
     highest_id: u64,
+    /// Which way round this set is studied by default
+    #[serde(default)]
+    pub direction: StudyDirection,
 }
 impl FlashcardSet {
     /// Creates a new, empty flashcard set
     pub const fn new(name: String) -> Self {
         Self {
+            id: SetId::UNASSIGNED,
             name,
             flashcards: vec![],
             highest_id: 0,
+            direction: StudyDirection::Forward,
         }
     }
+    /// Gets the id
+    pub const fn id(&self) -> SetId {
+        self.id
+    }
     /// Adds a flashcard to this set with the front and back [`RichText`]s
     pub fn add(&mut self, front: RichText, back: RichText) -> &Flashcard {
         let card = Flashcard {
             front,
             back,
             id: self.highest_id,
+            review: ReviewState::default(),
+            reverse_review: ReviewState::default(),
+            history: vec![],
         };
         self.flashcards.push(card);
         self.highest_id += 1;
         self.flashcards.last().unwrap()
     }
+    /// Reads a set from damaged save data, skipping any cards that can't be read.
+    /// Returns the set and how many cards were lost.
+    fn salvage(value: &Value) -> Option<(Self, usize)> {
+        if let Ok(set) = serde_json::from_value(value.clone()) {
+            return Some((set, 0));
+        }
+        let mut set = Self::new(value.get("name")?.as_str()?.into());
+        set.id = value.get("id").and_then(Value::as_u64).map_or(SetId::UNASSIGNED, SetId);
+        let mut lost = 0;
+        for card in value.get("flashcards").and_then(Value::as_array).into_iter().flatten() {
+            match serde_json::from_value::<Flashcard>(card.clone()) {
+                Ok(card) => {
+                    set.highest_id = set.highest_id.max(card.id + 1);
+                    set.flashcards.push(card);
+                }
+                Err(_) => lost += 1,
+            }
+        }
+        Some((set, lost))
+    }
+    /// Removes the flashcard with the given id, returning it
+    pub fn remove(&mut self, id: u64) -> Option<Flashcard> {
+        let index = self.flashcards.iter().position(|f| f.id == id)?;
+        Some(self.flashcards.remove(index))
+    }
 }
 impl Index<u64> for FlashcardSet {
     type Output = Flashcard;
@@ -98,8 +367,11 @@ impl IndexMut<u64> for FlashcardSet {
 }
 
 /// All the user's save data
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct UserData {
+    /// Version of the save format, see [`migrate`]
+    schema_version: u64,
+
     /// All of the user's flashcard sets
     pub sets: Vec<FlashcardSet>,
 
@@ -108,8 +380,37 @@ pub struct UserData {
     pub last_visit: u64,
 
     pub last_sys_time: Duration,
+
+    /// Total time spent in study sessions
+    #[serde(default)]
+    pub study_time: Duration,
+
+    /// The id the next added set will get
+    #[serde(default)]
+    next_set_id: u64,
+
+    /// Colours last picked for text, most recent first
+    #[serde(default, deserialize_with = "valid_colors")]
+    pub recent_colors: Vec<Color>,
+
+    /// Whether a side's sounds play by themselves when it's shown while studying
+    #[serde(default)]
+    pub autoplay_audio: bool,
+}
+/// Everything in [`UserData`] but the sets, borrowed so it can be saved on its own (see [`UserData::meta`])
+#[derive(Serialize)]
+pub struct UserMeta<'a> {
+    schema_version: u64,
+    duration_since_last_visit: &'a Duration,
+    last_visit: u64,
+    last_sys_time: &'a Duration,
+    study_time: &'a Duration,
+    next_set_id: u64,
+    recent_colors: &'a [Color],
+    autoplay_audio: bool,
 }
-/// A defualt user data i.e empty
+
+/// A defualt user data - the example sets
 impl Default for UserData {
     fn default() -> Self {
         let mut french = FlashcardSet::new("French".into());
@@ -130,62 +431,463 @@ impl Default for UserData {
             RichText::plaintext("Cockroach".into()),
             RichText::plaintext("Kakerlaken".into()),
         );
+        let mut this = Self::empty();
+        this.add_set(french);
+        this.add_set(german);
+        this
+    }
+}
+
+/// The user data couldn't be loaded
+#[derive(Clone, Debug)]
+pub struct LoadFailure {
+    /// What went wrong
+    pub error: String,
+    /// Where the unreadable file was moved to, so it's never saved over
+    pub quarantined: Option<PathBuf>,
+}
+
+/// What could be rescued from an unreadable save file
+pub struct Salvaged {
+    pub data: UserData,
+    /// Sets that couldn't be read at all
+    pub lost_sets: usize,
+    /// Cards that couldn't be read, from sets that otherwise could
+    pub lost_cards: usize,
+}
+
+impl UserData {
+    /// User data with no sets at all
+    pub fn empty() -> Self {
         Self {
-            sets: vec![french, german],
+            schema_version: migrate::CURRENT_VERSION,
+            sets: vec![],
             duration_since_last_visit: Duration::ZERO,
             last_visit: 0,
             last_sys_time: Duration::ZERO,
+            study_time: Duration::ZERO,
+            next_set_id: 1,
+            recent_colors: vec![],
+            autoplay_audio: false,
+        }
+    }
+    /// Finds the set with the given id
+    pub fn set(&self, id: SetId) -> Option<&FlashcardSet> {
+        self.sets.iter().find(|s| s.id == id)
+    }
+    /// Everything but the sets
+    pub fn meta(&self) -> UserMeta<'_> {
+        // Every field is named, so one can't be added without being saved here too
+        let Self {
+            schema_version,
+            sets: _,
+            duration_since_last_visit,
+            last_visit,
+            last_sys_time,
+            study_time,
+            next_set_id,
+            recent_colors,
+            autoplay_audio,
+        } = self;
+        UserMeta {
+            schema_version: *schema_version,
+            duration_since_last_visit,
+            last_visit: *last_visit,
+            last_sys_time,
+            study_time,
+            next_set_id: *next_set_id,
+            recent_colors,
+            autoplay_audio: *autoplay_audio,
+        }
+    }
+    /// A copy of everything but the sets, which are left empty
+    pub fn clone_meta(&self) -> Self {
+        // As in `meta`, every field is named so none is left behind
+        let Self {
+            schema_version,
+            sets: _,
+            duration_since_last_visit,
+            last_visit,
+            last_sys_time,
+            study_time,
+            next_set_id,
+            recent_colors,
+            autoplay_audio,
+        } = self;
+        Self {
+            schema_version: *schema_version,
+            sets: vec![],
+            duration_since_last_visit: *duration_since_last_visit,
+            last_visit: *last_visit,
+            last_sys_time: *last_sys_time,
+            study_time: *study_time,
+            next_set_id: *next_set_id,
+            recent_colors: recent_colors.clone(),
+            autoplay_audio: *autoplay_audio,
+        }
+    }
+    /// Takes everything but the sets from `meta`, e.g. a copy made by [`UserData::clone_meta`]
+    pub fn set_meta(&mut self, meta: Self) {
+        let sets = std::mem::take(&mut self.sets);
+        *self = Self { sets, ..meta };
+    }
+    /// Finds the set with the given id, mutably
+    pub fn set_mut(&mut self, id: SetId) -> Option<&mut FlashcardSet> {
+        self.sets.iter_mut().find(|s| s.id == id)
+    }
+    /// Finds the set with the given name
+    pub fn set_by_name(&self, name: &str) -> Option<&FlashcardSet> {
+        self.sets.iter().find(|s| s.name == name)
+    }
+    /// Whether a set other than `except` already has this name
+    pub fn name_taken(&self, name: &str, except: Option<SetId>) -> bool {
+        self.sets
+            .iter()
+            .any(|s| s.name == name && Some(s.id) != except)
+    }
+    /// `name`, or `name (2)`, `name (3)`... if it's already taken
+    pub fn unique_name(&self, name: &str) -> String {
+        let mut unique = name.to_owned();
+        let mut number = 2;
+        while self.name_taken(&unique, None) {
+            unique = format!("{} ({})", name, number);
+            number += 1;
+        }
+        unique
+    }
+    /// Adds a new set to the end of the list, giving it a fresh id
+    pub fn add_set(&mut self, mut set: FlashcardSet) -> SetId {
+        set.id = self.allocate_set_id();
+        let id = set.id;
+        self.sets.push(set);
+        id
+    }
+    /// Replaces the set with the same id, or adds it if there isn't one
+    pub fn replace_set(&mut self, set: FlashcardSet) -> SetId {
+        match self.set_mut(set.id) {
+            Some(existing) if set.id != SetId::UNASSIGNED => {
+                *existing = set;
+                existing.id
+            }
+            _ => self.add_set(set),
+        }
+    }
+    /// Saves a set from the editor, which works on a copy that may be out of date. Only what the editor
+    /// changes is taken from it - the name, direction, and the cards' text, order, additions and removals.
+    /// Scheduling and history are kept from the set as it is now, in case it was studied meanwhile.
+    pub fn save_edited_set(&mut self, mut edited: FlashcardSet) -> SetId {
+        if let Some(live) = self.set(edited.id).filter(|_| edited.id != SetId::UNASSIGNED) {
+            for card in &mut edited.flashcards {
+                if let Some(current) = live.flashcards.iter().find(|c| c.id == card.id) {
+                    card.review = current.review.clone();
+                    card.reverse_review = current.reverse_review.clone();
+                    card.history = current.history.clone();
+                }
+            }
+            edited.highest_id = edited.highest_id.max(live.highest_id);
+        }
+        self.replace_set(edited)
+    }
+    /// Removes a set, returning where it was in the list and the set itself
+    pub fn remove_set(&mut self, id: SetId) -> Option<(usize, FlashcardSet)> {
+        let index = self.sets.iter().position(|s| s.id == id)?;
+        Some((index, self.sets.remove(index)))
+    }
+    /// Puts a removed set back at `index`, keeping its id unless it's been reused
+    pub fn restore_set(&mut self, index: usize, mut set: FlashcardSet) -> SetId {
+        if set.id == SetId::UNASSIGNED || self.set(set.id).is_some() {
+            set.id = self.allocate_set_id();
+        }
+        self.next_set_id = self.next_set_id.max(set.id.0 + 1);
+        let id = set.id;
+        self.sets.insert(index.min(self.sets.len()), set);
+        id
+    }
+    /// Adds the sets of `other` after these ones, renaming any whose names are taken.
+    /// Returns how many were renamed.
+    pub fn merge(&mut self, other: Self) -> usize {
+        let mut renamed = 0;
+        for mut set in other.sets {
+            let name = self.unique_name(&set.name);
+            if name != set.name {
+                set.name = name;
+                renamed += 1;
+            }
+            self.add_set(set);
+        }
+        self.study_time += other.study_time;
+        renamed
+    }
+    /// Puts a colour at the front of the recently used colours
+    pub fn remember_color(&mut self, color: &Color) {
+        self.recent_colors.retain(|c| c != color);
+        self.recent_colors.insert(0, color.clone());
+        self.recent_colors.truncate(MAX_RECENT_COLORS);
+    }
+    /// Gets a new, unused set id
+    fn allocate_set_id(&mut self) -> SetId {
+        // Ids are never reused, even after a set is deleted
+        let highest = self.sets.iter().map(|s| s.id.0).max().unwrap_or(0);
+        self.next_set_id = self.next_set_id.max(highest + 1);
+        let id = SetId(self.next_set_id);
+        self.next_set_id += 1;
+        id
+    }
+    /// Loads data saved in a file
+    pub fn load_from(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Self::from_json(serde_json::from_reader(BufReader::new(file))?)
+    }
+    /// Deserialises data saved by this or an older version of the app
+    pub fn from_json(data: Value) -> io::Result<Self> {
+        Ok(serde_json::from_value(migrate::migrate(data)?)?)
+    }
+    /// Rescues whatever sets and cards can still be read from a damaged save file,
+    /// including one that was cut short
+    pub fn salvage(path: &Path) -> io::Result<Salvaged> {
+        let bytes = fs::read(path)?;
+        // A file cut short can end part way through a character, or be padded with zeros
+        let text = String::from_utf8_lossy(&bytes);
+        let text = text.trim_end_matches(|c: char| c == '\0' || c.is_whitespace());
+        let value: Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) if e.is_eof() => serde_json::from_str(&close_truncated_json(text).ok_or(e)?)?,
+            Err(e) => return Err(e.into()),
+        };
+        // Migrating may fail on the damaged parts - then just read what's there
+        let value = migrate::migrate(value.clone()).unwrap_or(value);
+        let mut salvaged = Salvaged {
+            data: Self::empty(),
+            lost_sets: 0,
+            lost_cards: 0,
+        };
+        let sets = value.get("sets").and_then(Value::as_array).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no sets could be found")
+        })?;
+        for set in sets {
+            match FlashcardSet::salvage(set) {
+                Some((set, lost_cards)) => {
+                    let index = salvaged.data.sets.len();
+                    salvaged.data.restore_set(index, set);
+                    salvaged.lost_cards += lost_cards;
+                }
+                None => salvaged.lost_sets += 1,
+            }
         }
+        if let Some(time) = value.get("study_time").and_then(|t| serde_json::from_value(t.clone()).ok()) {
+            salvaged.data.study_time = time;
+        }
+        Ok(salvaged)
     }
 }
-impl UserData {
-    /// Save and serialise this data
-    pub fn save(&self) -> io::Result<()> {
-        info!("Saving user data");
-        // Get some platform-specific save dirs
-        let app_dirs = AppDirs::new(Some("magistrax"), true).unwrap();
-        let data_path = app_dirs.data_dir.join("user-data.json");
-        // Ensure all folders exist
-        fs::create_dir_all(&app_dirs.data_dir)?;
-        // Create (or truncate) & write
-        let file = File::create(data_path)?;
-        serde_json::to_writer(file, self)?;
-        Ok(())
-    }
-    /// Load and deserialise this data
-    pub fn load() -> io::Result<Self> {
-        info!("Loading user data");
-        // Get some platform-specific save dirs
-        let app_dirs = AppDirs::new(Some("magistrax"), true).unwrap();
-        let data_path = app_dirs.data_dir.join("user-data.json");
-        let this: Self;
-        // Attempt to load this file
-        if data_path.exists() {
-            let file = File::open(data_path)?;
-            this = serde_json::from_reader(file)?;
-        } else {
-            this = Self::default();
-            this.save()?;
+
+/// Closes off JSON that was cut short, keeping every value that was finished.
+/// Returns `None` if nothing inside the outermost value was finished.
+fn close_truncated_json(text: &str) -> Option<String> {
+    // Brackets that still need closing, innermost last
+    let mut open = String::new();
+    // The last place the text could be cut and closed, and the brackets that would need closing there
+    let mut cut = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            '}' | ']' => {
+                open.pop();
+                if !open.is_empty() {
+                    cut = Some((i + 1, open.clone()));
+                }
+            }
+            // Everything before a comma is finished
+            ',' => cut = Some((i, open.clone())),
+            _ => {}
         }
-        Ok(this)
     }
+    let (end, open) = cut?;
+    let mut closed = text[..end].to_owned();
+    closed.extend(open.chars().rev());
+    Some(closed)
 }
 
 /// Encapsulates [`UserData`] to ensure it is saved after modification.
+/// Saving happens in the background - see [`Autosave`].
 pub struct UserDataAccessor {
     data: UserData,
+    autosave: Autosave,
+    /// Set if the data couldn't be loaded - nothing is saved until the user decides what to recover
+    recovery: Option<LoadFailure>,
 }
 impl UserDataAccessor {
-    pub const fn new(data: UserData) -> Self {
-        Self { data }
+    /// Loads the data from `storage`, waiting for the user to recover it if it can't be loaded
+    pub fn open(mut storage: Box<dyn Storage>) -> Self {
+        let (data, recovery) = match storage.load() {
+            Ok(data) => (data, None),
+            // Hold no sets until it's recovered
+            Err(failure) => (UserData::empty(), Some(failure)),
+        };
+        let autosave = Autosave::start(storage, &data);
+        Self {
+            data,
+            autosave,
+            recovery,
+        }
     }
     pub const fn get(&self) -> &UserData {
         &self.data
     }
+    /// Where the data is stored
+    pub fn storage(&self) -> MutexGuard<'_, Box<dyn Storage>> {
+        self.autosave.storage()
+    }
+    /// Why the data couldn't be loaded, if it's waiting to be recovered
+    pub const fn recovery(&self) -> Option<&LoadFailure> {
+        self.recovery.as_ref()
+    }
+    /// Why the last save failed, if it did
+    pub fn save_error(&self) -> Option<String> {
+        self.autosave.error()
+    }
+    /// Writes any changes that haven't been saved yet, waiting until they are
+    pub fn flush(&self) {
+        self.autosave.flush();
+    }
+    /// Replaces the data with what the user chose to recover, and starts saving again
+    pub fn recover(&mut self, data: UserData) {
+        self.recovery = None;
+        self.modify(|d| *d = data);
+    }
+    /// Queues the change to be saved, unless the data is waiting to be recovered
+    fn changed(&self, change: Change) {
+        if self.recovery.is_some() {
+            warn!("Not saving - user data is waiting to be recovered");
+        } else {
+            self.autosave.changed(&self.data, change);
+        }
+    }
+    /// Changes anything in the data, then saves all of it
     pub fn modify(&mut self, f: impl FnOnce(&mut UserData)) {
         f(&mut self.data);
-        if let Err(e) = self.data.save() {
-            error!("Could not save data: {}", e);
+        self.changed(Change::All);
+    }
+    /// Changes one set, then saves just that set
+    pub fn modify_set(&mut self, id: SetId, f: impl FnOnce(&mut FlashcardSet)) {
+        if let Some(set) = self.data.set_mut(id) {
+            f(set);
+            self.changed(Change::Set(id));
+        }
+    }
+    /// Changes one card of a set, e.g. reviewing it, then saves just that card
+    pub fn modify_card(&mut self, set: SetId, card: u64, f: impl FnOnce(&mut Flashcard)) {
+        let flashcard = self.data.set_mut(set).and_then(|s| s.flashcards.iter_mut().find(|c| c.id == card));
+        if let Some(flashcard) = flashcard {
+            f(flashcard);
+            self.changed(Change::Card(set, card));
         }
     }
+    /// Changes everything but the sets, e.g. the study time, then saves it
+    pub fn modify_meta(&mut self, f: impl FnOnce(&mut UserData)) {
+        f(&mut self.data);
+        self.changed(Change::Meta);
+    }
+    /// Replaces the set with the same id, or adds it, and saves it
+    pub fn replace_set(&mut self, set: FlashcardSet) -> SetId {
+        let id = self.data.replace_set(set);
+        self.changed(Change::Set(id));
+        id
+    }
+    /// Saves a set from the editor, keeping its cards' scheduling as it is now (see [`UserData::save_edited_set`])
+    pub fn save_edited_set(&mut self, set: FlashcardSet) -> SetId {
+        let id = self.data.save_edited_set(set);
+        self.changed(Change::Set(id));
+        id
+    }
+    /// Removes a set and deletes it from storage
+    pub fn remove_set(&mut self, id: SetId) -> Option<(usize, FlashcardSet)> {
+        let removed = self.data.remove_set(id)?;
+        self.changed(Change::RemovedSet(id));
+        Some(removed)
+    }
+    /// Puts a removed set back and saves it
+    pub fn restore_set(&mut self, index: usize, set: FlashcardSet) -> SetId {
+        let id = self.data.restore_set(index, set);
+        self.changed(Change::Set(id));
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saving_an_edited_set_keeps_newer_scheduling() {
+        let mut data = UserData::default();
+        let id = data.sets[0].id;
+        // Opened in the editor, then studied before the edits are saved
+        let mut edited = data.set(id).unwrap().clone();
+        data.set_mut(id).unwrap().flashcards[0].record_review(Direction::Forward, Grade::Good, 1000);
+        edited.name = "Français".into();
+        edited.flashcards[0].back = RichText::plaintext("J'habite à Paris".into());
+        edited.flashcards.swap(0, 1);
+        edited.add(RichText::plaintext("I have".into()), RichText::plaintext("J'ai".into()));
+
+        data.save_edited_set(edited);
+        let set = data.set(id).unwrap();
+        assert_eq!(set.name, "Français");
+        assert_eq!(set.flashcards.len(), 3);
+        let studied = &set.flashcards[1];
+        assert_eq!(studied.back, RichText::plaintext("J'habite à Paris".into()));
+        assert_eq!(studied.history.len(), 1);
+        assert_ne!(studied.review, ReviewState::default());
+    }
+
+    #[test]
+    fn salvages_truncated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        let json = serde_json::to_string(&UserData::default()).unwrap();
+        // Cut off part way through the second card of the second set
+        let cut = json.rfind("Kakerlaken").unwrap();
+        fs::write(&path, &json[..cut]).unwrap();
+        assert!(UserData::load_from(&path).is_err());
+
+        let salvaged = UserData::salvage(&path).unwrap();
+        let names: Vec<&str> = salvaged.data.sets.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["French", "German"]);
+        assert_eq!(salvaged.data.sets[0].flashcards.len(), 2);
+        assert_eq!(salvaged.data.sets[1].flashcards.len(), 1);
+        assert_eq!(salvaged.lost_cards, 1);
+    }
+
+    #[test]
+    fn salvages_files_padded_with_zeros() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        let mut bytes = serde_json::to_vec(&UserData::default()).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        bytes.extend([0; 64]);
+        fs::write(&path, bytes).unwrap();
+        let salvaged = UserData::salvage(&path).unwrap();
+        assert_eq!(salvaged.data.sets[0].name, "French");
+    }
+
+    #[test]
+    fn closes_truncated_json() {
+        assert_eq!(close_truncated_json(r#"{"a": [1, 2, {"b": "x,"#).as_deref(), Some(r#"{"a": [1, 2]}"#));
+        assert_eq!(close_truncated_json(r#"{"a": {"b": [1]}, "c": "#).as_deref(), Some(r#"{"a": {"b": [1]}}"#));
+        assert_eq!(close_truncated_json(r#"{"a": "\"}, [""#), None);
+    }
 }
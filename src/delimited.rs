@@ -0,0 +1,169 @@
+//! Reading and writing flashcards as delimited text - CSV, TSV and the like
+use std::{io, path::Path};
+
+use crate::data::{FlashcardSet, RichText};
+
+/// How a delimited file is laid out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Layout {
+    /// What separates the fields of a row
+    pub separator: u8,
+    /// Whether the first row names the columns
+    pub has_header: bool,
+}
+impl Layout {
+    /// Guesses the layout from a file's extension - tabs for `.tsv` and `.tab`, commas otherwise
+    pub fn for_path(path: &Path) -> Self {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        Self {
+            separator: if matches!(extension.to_lowercase().as_str(), "tsv" | "tab") {
+                b'\t'
+            } else {
+                b','
+            },
+            has_header: false,
+        }
+    }
+}
+
+/// The rows of a delimited file
+pub struct Table {
+    /// Names of the columns, if the file had a header row
+    pub header: Option<Vec<String>>,
+    pub rows: Vec<Vec<String>>,
+}
+impl Table {
+    /// How many columns the widest row has
+    pub fn columns(&self) -> usize {
+        self.header
+            .iter()
+            .chain(&self.rows)
+            .map(Vec::len)
+            .max()
+            .unwrap_or(0)
+    }
+    /// Name of a column, from the header if there is one
+    pub fn column_name(&self, column: usize) -> String {
+        match self.header.as_ref().and_then(|h| h.get(column)) {
+            Some(name) if !name.trim().is_empty() => name.clone(),
+            _ => format!("Column {}", column + 1),
+        }
+    }
+}
+
+/// Which columns become which side of the cards
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ColumnMapping {
+    pub front: usize,
+    pub back: usize,
+}
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self { front: 0, back: 1 }
+    }
+}
+
+/// Reads delimited text. Fields may be quoted, and quoted fields may span several lines.
+pub fn parse(text: &str, layout: Layout) -> io::Result<Table> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(layout.separator)
+        .has_headers(layout.has_header)
+        // Rows don't all need every column
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let header = if layout.has_header {
+        Some(reader.headers()?.iter().map(String::from).collect())
+    } else {
+        None
+    };
+    let rows = reader
+        .records()
+        .map(|row| row.map(|row| row.iter().map(String::from).collect()))
+        .collect::<Result<_, _>>()?;
+    Ok(Table { header, rows })
+}
+
+/// Makes a new set from the rows of a table, skipping rows with nothing in either column
+pub fn to_set(name: String, table: &Table, mapping: ColumnMapping) -> FlashcardSet {
+    let mut set = FlashcardSet::new(name);
+    let field = |row: &[String], column: usize| row.get(column).map_or("", |f| f.trim()).to_owned();
+    for row in &table.rows {
+        let (front, back) = (field(row, mapping.front), field(row, mapping.back));
+        if !front.is_empty() || !back.is_empty() {
+            set.add(RichText::plaintext(front), RichText::plaintext(back));
+        }
+    }
+    set
+}
+
+/// Writes a set as delimited text, with a `front`/`back` header row
+pub fn write(set: &FlashcardSet, separator: u8) -> io::Result<Vec<u8>> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(separator)
+        .from_writer(vec![]);
+    writer.write_record(["front", "back"])?;
+    for card in &set.flashcards {
+        writer.write_record([&card.front.text, &card.back.text])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sides(set: &FlashcardSet) -> Vec<(&str, &str)> {
+        set.flashcards.iter().map(|c| (c.front.text.as_str(), c.back.text.as_str())).collect()
+    }
+
+    #[test]
+    fn reads_quoted_fields() {
+        let text = "\"one, two\",\"line\nbreak\"\n\"say \"\"hi\"\"\",plain\n";
+        let layout = Layout { separator: b',', has_header: false };
+        let table = parse(text, layout).unwrap();
+        assert_eq!(table.header, None);
+        assert_eq!(table.rows, [vec!["one, two", "line\nbreak"], vec!["say \"hi\"", "plain"]]);
+    }
+
+    #[test]
+    fn reads_headers_and_other_separators() {
+        let layout = Layout { separator: b';', has_header: true };
+        let table = parse("Deutsch;English;Notes\nHund;dog\n", layout).unwrap();
+        assert_eq!(table.header.as_deref(), Some(&["Deutsch".to_owned(), "English".into(), "Notes".into()][..]));
+        assert_eq!(table.rows, [vec!["Hund", "dog"]]);
+        assert_eq!(table.columns(), 3);
+        assert_eq!(table.column_name(1), "English");
+        assert_eq!(table.column_name(3), "Column 4");
+
+        let tsv = Layout::for_path(Path::new("words.TSV"));
+        assert_eq!(tsv, Layout { separator: b'\t', has_header: false });
+        assert_eq!(Layout::for_path(Path::new("words.csv")).separator, b',');
+        let table = parse("a, b\tc\n", tsv).unwrap();
+        assert_eq!(table.rows, [vec!["a, b", "c"]]);
+    }
+
+    #[test]
+    fn skips_rows_with_nothing_to_study() {
+        let layout = Layout { separator: b',', has_header: false };
+        let table = parse("front only\n,\n  ,  \n\n,back only\n a , b \n", layout).unwrap();
+        let set = to_set("Set".into(), &table, ColumnMapping::default());
+        assert_eq!(sides(&set), [("front only", ""), ("", "back only"), ("a", "b")]);
+        // Columns past the end of a short row are empty
+        let swapped = to_set("Set".into(), &table, ColumnMapping { front: 2, back: 0 });
+        assert_eq!(sides(&swapped), [("", "front only"), ("", "a")]);
+    }
+
+    #[test]
+    fn round_trips_sets() {
+        let mut set = FlashcardSet::new("Set".into());
+        for (front, back) in [("plain", "text"), ("comma, here", "\"quoted\""), ("two\nlines", "tab\there")] {
+            set.add(RichText::plaintext(front.into()), RichText::plaintext(back.into()));
+        }
+        for separator in [b',', b'\t', b';'] {
+            let bytes = write(&set, separator).unwrap();
+            let table = parse(std::str::from_utf8(&bytes).unwrap(), Layout { separator, has_header: true }).unwrap();
+            assert_eq!(table.header, Some(vec!["front".to_owned(), "back".into()]));
+            assert_eq!(sides(&to_set("Set".into(), &table, ColumnMapping::default())), sides(&set));
+        }
+    }
+}
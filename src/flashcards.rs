@@ -1,19 +1,47 @@
-use std::cell::Ref;
+use std::{
+    cell::Ref,
+    time::{Duration, Instant},
+};
 
-use crate::{CurrentPage, PageLink, USER_DATA};
-use dioxus::{fermi::use_read, prelude::*};
+use crate::{data::{FlashcardSet, SetId}, import, storage, CurrentPage, PageLink, USER_DATA};
+use dioxus::{
+    fermi::{use_read, use_set, Atom},
+    prelude::*,
+};
+
+/// How long a deleted set can be brought back for
+const UNDO_TIME: Duration = Duration::from_secs(10);
+
+/// A recently deleted set, kept so the deletion can be undone
+#[derive(Clone)]
+pub(crate) struct DeletedSet {
+    set: FlashcardSet,
+    /// Where the set was in the list
+    index: usize,
+    deleted_at: Instant,
+}
+
+/// The last deleted set
+pub(crate) static DELETED_SET: Atom<Option<DeletedSet>> = |_| None;
 
 #[derive(Props, PartialEq)]
 struct StudySetProps {
-    /// A reference to the flashcard set to render
-    set: String,
+    /// The flashcard set to render
+    set: SetId,
 }
 /// Render a preview of a study set
 fn StudySet(cx: Scope<StudySetProps>) -> Element {
 
     let user_data = use_read(&cx, USER_DATA);
-    let sets = Ref::map(user_data.borrow(), |d| &d.get().sets);
-    let set = sets.iter().find(|s| s.name == cx.props.set).unwrap();
+    let set_deleted = use_set(&cx, DELETED_SET);
+    let (confirming, set_confirming) = use_state(&cx, || false);
+    let (export_message, set_export_message) = use_state(&cx, || None::<String>);
+    let data = Ref::map(user_data.borrow(), |d| d.get());
+    let set = match data.set(cx.props.set) {
+        Some(set) => set,
+        // Just deleted
+        None => return None,
+    };
 
     rsx!(cx,
         button {
@@ -22,24 +50,100 @@ fn StudySet(cx: Scope<StudySetProps>) -> Element {
             h2 {"{set.name}"}
         },
 
-        button{
-            "type": "button",
-            class: "delete-button",
+        PageLink {
+            class: "edit-button",
+            name: "Edit",
+            redirect: CurrentPage::NoteInput(Some(set.id()))
+        },
 
-            // onclick: move |_| {                
+        button {
+            "type": "button",
+            class: "edit-button",
+            onclick: move |_| {
+                let set = user_data.borrow().get().set(cx.props.set).cloned();
+                let media_dir = storage::media_dir(user_data.borrow().storage().path());
+                if let Some(set) = set {
+                    set_export_message(match import::export_set(&set, &media_dir) {
+                        Ok(Some(exported)) => Some(exported.message()),
+                        Ok(None) => None,
+                        Err(e) => Some(format!("Couldn't export {}: {}", set.name, e)),
+                    });
+                }
+            },
+            "Export"
+        },
+        export_message.as_ref().map(|m| rsx!(cx, p {"{m}"}))
 
-            //     {let index = user_data.clone().borrow_mut().get().sets.iter().position(|s| s.name == set.name).unwrap();
+        confirming.then(|| rsx!(cx, div {
+            class: "delete-confirm",
+            p {"Delete {set.name}?"}
+            button {
+                "type": "button",
+                onclick: move |_| {
+                    let removed = user_data.borrow_mut().remove_set(cx.props.set);
+                    if let Some((index, set)) = removed {
+                        set_deleted(Some(DeletedSet {
+                            set,
+                            index,
+                            deleted_at: Instant::now(),
+                        }));
+                    }
+                    set_confirming(false);
+                },
+                "Yes"
+            }
+            button {
+                "type": "button",
+                onclick: move |_| set_confirming(false),
+                "No"
+            }
+        }))
 
-            //     user_data.borrow_mut().modify(|d|{ d.sets.remove(index);});
-            //     }
-            // },
-            // "Save",
+        (!confirming).then(|| rsx!(cx, button {
+            "type": "button",
+            class: "delete-button",
+            onclick: move |_| set_confirming(true),
             h2 {"Delete"}
-        }
-
+        }))
     )
 }
 
+/// A banner to bring back the last deleted set, shown for [`UNDO_TIME`]
+fn UndoDelete(cx: Scope) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    let deleted = use_read(&cx, DELETED_SET);
+    let set_deleted = use_set(&cx, DELETED_SET);
+    // Re-render every second to count down (and hide the banner once the time is up)
+    let update = cx.schedule_update();
+    use_future(&cx, || async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            update();
+        }
+    });
+    let deleted_set = deleted.as_ref().filter(|d| d.deleted_at.elapsed() < UNDO_TIME)?;
+    let seconds_left = (UNDO_TIME - deleted_set.deleted_at.elapsed()).as_secs() + 1;
+    rsx!(cx, div {
+        class: "undo-banner",
+        "Deleted {deleted_set.set.name} "
+        button {
+            "type": "button",
+            onclick: move |_| {
+                if let Some(mut restored) = deleted.clone() {
+                    let mut user_data = user_data.borrow_mut();
+                    // Someone may have taken the name since
+                    while user_data.get().name_taken(&restored.set.name, None) {
+                        restored.set.name.push_str(" (restored)");
+                    }
+                    user_data.restore_set(restored.index, restored.set);
+                    set_deleted(None);
+                }
+            },
+            "Undo ({seconds_left}s)"
+        }
+    })
+}
+
 /// System to track time taken to return to a page.
 /// Made as a component so we can implement it 
 /// anywhere in our code. This will be used to show users how they are doing
@@ -87,8 +191,12 @@ fn StudySet(cx: Scope<StudySetProps>) -> Element {
 // }
 
 pub fn Flashcards(cx: Scope) -> Element {
-    let user_data_borrow = use_read(&cx, USER_DATA).borrow();
+    let user_data_atom = use_read(&cx, USER_DATA);
+    let user_data_borrow = user_data_atom.borrow();
     let user_data = user_data_borrow.get();
+    let (export_message, set_export_message) = use_state(&cx, || None::<String>);
+    // Re-render the list when a set is deleted or restored
+    use_read(&cx, DELETED_SET);
 
     let sets = &user_data.sets;
     let study_set_previews: Vec<Element> = sets.iter().map(|s|
@@ -96,8 +204,8 @@ pub fn Flashcards(cx: Scope) -> Element {
         div {
         "class": "set-list-item",
         StudySet {
-            set: s.name.clone(),
-            key: "{s.name}"
+            set: s.id(),
+            key: "{s.id()}"
         },
     }
     ))
@@ -106,6 +214,7 @@ pub fn Flashcards(cx: Scope) -> Element {
         div {
             class: "center-div",
             h1 {"Flashcards"},
+            UndoDelete {}
             div {
                 class: "row-flex",
                 div {
@@ -114,8 +223,29 @@ pub fn Flashcards(cx: Scope) -> Element {
                     PageLink {
                         class: "set-list-item study-set-create",
                         name: "Create a study set",
-                        redirect: CurrentPage::NoteInput
+                        redirect: CurrentPage::NoteInput(None)
+                    },
+                    PageLink {
+                        class: "set-list-item study-set-create",
+                        name: "Import a study set",
+                        redirect: CurrentPage::Import
+                    },
+                    button {
+                        "type": "button",
+                        class: "set-list-item study-set-create",
+                        onclick: move |_| {
+                            let user_data = user_data_atom.borrow();
+                            let media_dir = storage::media_dir(user_data.storage().path());
+                            let result = import::export_all(&user_data.get().sets, &media_dir);
+                            set_export_message(match result {
+                                Ok(Some(exported)) => Some(exported.message()),
+                                Ok(None) => None,
+                                Err(e) => Some(format!("Couldn't export your sets: {}", e)),
+                            });
+                        },
+                        "Export all sets for Anki"
                     },
+                    export_message.as_ref().map(|m| rsx!(cx, p {"{m}"}))
                     study_set_previews
                 },
                 div {class: "divider"}
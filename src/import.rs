@@ -0,0 +1,431 @@
+//! Bringing sets in from other files, and sending them back out
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use dioxus::{
+    fermi::{use_read, use_set},
+    prelude::*,
+};
+use log::{info, warn};
+
+use crate::{
+    anki,
+    data::FlashcardSet,
+    delimited::{self, ColumnMapping, Layout},
+    markdown,
+    media::{self, MediaKind},
+    storage, CurrentPage, CURRENT_PAGE, USER_DATA,
+};
+
+/// How many rows are shown in the preview
+const PREVIEW_ROWS: usize = 10;
+
+/// Separators that can be picked from the list, by name
+const SEPARATORS: [(&str, u8); 4] = [("Comma", b','), ("Tab", b'\t'), ("Semicolon", b';'), ("Bar", b'|')];
+
+/// A file picked for importing
+enum Source {
+    /// A spreadsheet, read as text so it can be parsed again when the layout changes
+    Delimited { path: PathBuf, text: String },
+    /// An Anki package or a Markdown deck, with the sets read from it
+    Sets {
+        path: PathBuf,
+        sets: Vec<FlashcardSet>,
+        /// Images and sounds that were left out
+        skipped_media: usize,
+    },
+}
+impl Source {
+    /// Reads a file, choosing how from its extension. Images and sounds it has are added to the store in `media_dir`.
+    fn read(path: PathBuf, media_dir: &Path) -> io::Result<Self> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+        Ok(match extension.as_str() {
+            "apkg" | "colpkg" => {
+                let package = anki::import(&path, media_dir)?;
+                Self::Sets {
+                    sets: package.sets,
+                    skipped_media: package.skipped_media,
+                    path,
+                }
+            }
+            "md" | "markdown" => {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                let mut sets = markdown::parse(&read_text(&path)?, &name)?;
+                Self::Sets {
+                    skipped_media: add_markdown_media(&mut sets, &path, media_dir),
+                    sets,
+                    path,
+                }
+            }
+            _ => Self::Delimited {
+                text: read_text(&path)?,
+                path,
+            },
+        })
+    }
+    fn path(&self) -> &Path {
+        match self {
+            Self::Delimited { path, .. } | Self::Sets { path, .. } => path,
+        }
+    }
+}
+
+/// Reads a text file, dropping the byte order mark spreadsheet programs like to add
+fn read_text(path: &Path) -> io::Result<String> {
+    let text = fs::read_to_string(path)?;
+    Ok(text.strip_prefix('\u{feff}').map(String::from).unwrap_or(text))
+}
+
+/// Adds the pictures and sounds a Markdown deck uses, found by their paths from the deck, to the store.
+/// Their paths are replaced by their names in the store. Returns how many couldn't be added.
+fn add_markdown_media(sets: &mut [FlashcardSet], deck: &Path, media_dir: &Path) -> usize {
+    let dir = deck.parent().unwrap_or_else(|| Path::new("."));
+    let mut missing = 0;
+    for text in sets.iter_mut().flat_map(|s| &mut s.flashcards).flat_map(|c| [&mut c.front, &mut c.back]) {
+        for (paths, expected) in [(&mut text.images, MediaKind::Image), (&mut text.audio, MediaKind::Audio)] {
+            paths.retain_mut(|path| match media::add_file(media_dir, &dir.join(&*path)) {
+                Ok((kind, name)) if kind == expected => {
+                    *path = name;
+                    true
+                }
+                Ok(_) => {
+                    warn!("{} isn't the right kind of file for where it's used", path);
+                    missing += 1;
+                    false
+                }
+                Err(e) => {
+                    warn!("Could not add {}: {}", path, e);
+                    missing += 1;
+                    false
+                }
+            });
+        }
+    }
+    missing
+}
+
+/// Copies the pictures and sounds the sets use into the media folder next to a Markdown deck.
+/// Returns how many couldn't be copied.
+fn copy_markdown_media(sets: &[FlashcardSet], deck: &Path, media_dir: &Path) -> io::Result<usize> {
+    let folder = deck.with_file_name(markdown::MEDIA_FOLDER);
+    let mut missing = 0;
+    for name in sets.iter().flat_map(|s| &s.flashcards).flat_map(|c| c.front.media().chain(c.back.media())) {
+        fs::create_dir_all(&folder)?;
+        // The name says what's in the file, so one that's there already is the same
+        if folder.join(name).exists() {
+            continue;
+        }
+        if let Err(e) = fs::copy(media_dir.join(name), folder.join(name)) {
+            warn!("Could not copy {}: {}", name, e);
+            missing += 1;
+        }
+    }
+    Ok(missing)
+}
+
+/// Where sets were exported to, and how many of their pictures and sounds had to be left out
+pub struct Exported {
+    pub path: PathBuf,
+    pub left_out: usize,
+}
+impl Exported {
+    /// Tells the user where the sets went
+    pub fn message(&self) -> String {
+        match self.left_out {
+            0 => format!("Exported to {}", self.path.display()),
+            n => format!(
+                "Exported to {} - {} pictures and sounds were left out, as they're missing or the file can only hold text",
+                self.path.display(),
+                n
+            ),
+        }
+    }
+}
+
+/// A file name for a set - without characters that aren't allowed in file names
+fn file_name(name: &str, extension: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, ' ' | '-' | '_') { c } else { '_' })
+        .collect();
+    format!("{}.{}", name.trim(), extension)
+}
+
+/// Asks where to save a set, then writes it in the format matching the file extension, with the
+/// pictures and sounds from `media_dir` if it can hold them. Returns `None` if the user cancelled.
+pub fn export_set(set: &FlashcardSet, media_dir: &Path) -> io::Result<Option<Exported>> {
+    let path = match rfd::FileDialog::new()
+        .set_file_name(&file_name(&set.name, "csv"))
+        .add_filter("Comma separated", &["csv"])
+        .add_filter("Tab separated", &["tsv"])
+        .add_filter("Anki package", &["apkg"])
+        .add_filter("Markdown", &["md"])
+        .save_file()
+    {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    info!("Exporting {} to {}", set.name, path.display());
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+    let sets = std::slice::from_ref(set);
+    let left_out = match extension.as_str() {
+        "apkg" => anki::export(sets, &path, media_dir)?,
+        "md" | "markdown" => {
+            fs::write(&path, markdown::write(sets))?;
+            copy_markdown_media(sets, &path, media_dir)?
+        }
+        _ => {
+            fs::write(&path, delimited::write(set, Layout::for_path(&path).separator)?)?;
+            // Spreadsheets only hold the text
+            set.flashcards.iter().map(|c| c.front.media().chain(c.back.media()).count()).sum()
+        }
+    };
+    Ok(Some(Exported { path, left_out }))
+}
+
+/// Asks where to save every set - as an Anki package or a Markdown deck - then writes them,
+/// with the pictures and sounds from `media_dir`. Returns `None` if the user cancelled.
+pub fn export_all(sets: &[FlashcardSet], media_dir: &Path) -> io::Result<Option<Exported>> {
+    let path = match rfd::FileDialog::new()
+        .set_file_name(&file_name("Magistrax", "apkg"))
+        .add_filter("Anki package", &["apkg"])
+        .add_filter("Markdown", &["md"])
+        .save_file()
+    {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let left_out = if path.extension().map_or(false, |e| e.eq_ignore_ascii_case("md")) {
+        fs::write(&path, markdown::write(sets))?;
+        copy_markdown_media(sets, &path, media_dir)?
+    } else {
+        anki::export(sets, &path, media_dir)?
+    };
+    Ok(Some(Exported { path, left_out }))
+}
+
+/// Page for importing sets from other files.
+/// Spreadsheets get a preview to choose the columns from, Anki packages and Markdown decks a list of their sets.
+pub fn Import(cx: Scope) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    let set_page = use_set(&cx, CURRENT_PAGE);
+    let source = use_ref(&cx, || None::<Source>);
+    let (layout, set_layout) = use_state(&cx, || Layout {
+        separator: b',',
+        has_header: false,
+    });
+    // Set when the separator is typed in rather than picked from the list
+    let (custom_separator, set_custom_separator) = use_state(&cx, || false);
+    let (mapping, set_mapping) = use_state(&cx, ColumnMapping::default);
+    let (name, set_name) = use_state(&cx, String::new);
+    let (message, set_message) = use_state(&cx, || None::<String>);
+
+    let choose_file = move |_| {
+        let path = rfd::FileDialog::new()
+            .add_filter("Spreadsheets, Anki packages and Markdown decks", &["csv", "tsv", "tab", "txt", "apkg", "colpkg", "md", "markdown"])
+            .pick_file();
+        if let Some(path) = path {
+            let media_dir = storage::media_dir(user_data.borrow().storage().path());
+            match Source::read(path, &media_dir) {
+                Ok(read) => {
+                    set_layout(Layout::for_path(read.path()));
+                    set_custom_separator(false);
+                    set_mapping(ColumnMapping::default());
+                    set_name(read.path().file_stem().unwrap_or_default().to_string_lossy().into_owned());
+                    set_message(None);
+                    *source.write() = Some(read);
+                }
+                Err(e) => set_message(Some(format!("That file couldn't be read: {}", e))),
+            }
+        }
+    };
+
+    let source_borrow = source.read();
+    let picked = source_borrow
+        .as_ref()
+        .map(|s| s.path().display().to_string());
+
+    let form = match &*source_borrow {
+        None => rsx!(cx, p {"Choose a CSV or TSV file, an Anki package or a Markdown deck to import."}),
+        Some(Source::Sets { sets, skipped_media, .. }) => {
+            let set_count = sets.len();
+            let card_count: usize = sets.iter().map(|s| s.flashcards.len()).sum();
+            let skipped = *skipped_media;
+            rsx!(cx, div {
+                p {"{card_count} cards in {set_count} sets:"}
+                ul {
+                    sets.iter().enumerate().map(|(i, set)| {
+                        let count = set.flashcards.len();
+                        rsx!(cx, li { key: "{i}", "{set.name} ({count} cards)" })
+                    })
+                }
+                (skipped > 0).then(|| rsx!(cx, p {
+                    class: "warning",
+                    "{skipped} images and sounds the cards use are missing from the package, or can't be shown, so they were left out."
+                }))
+                button {
+                    "type": "button",
+                    disabled: "{set_count == 0}",
+                    onclick: move |_| {
+                        if let Some(Source::Sets { sets, .. }) = source.write().take() {
+                            let mut user_data = user_data.borrow_mut();
+                            for mut set in sets {
+                                // Keep existing sets as they are
+                                set.name = user_data.get().unique_name(&set.name);
+                                user_data.replace_set(set);
+                            }
+                            set_page(CurrentPage::Flashcards);
+                        }
+                    },
+                    "Import {set_count} sets"
+                }
+            })
+        }
+        Some(Source::Delimited { text, .. }) => {
+            let table = delimited::parse(text, *layout);
+            let can_import = table.is_ok();
+            let preview = match &table {
+                Err(e) => rsx!(cx, p { class: "warning", "The file couldn't be read with these settings: {e}" }),
+                Ok(parsed) => {
+                    let columns = parsed.columns();
+                    let column_select = |selected: usize, set: fn(ColumnMapping, usize) -> ColumnMapping| rsx!(cx, select {
+                        oninput: move |e| {
+                            if let Ok(column) = e.value.parse() {
+                                set_mapping(set(*mapping, column));
+                            }
+                        },
+                        (0..columns).map(|c| {
+                            let column_name = parsed.column_name(c);
+                            rsx!(cx, option {
+                                key: "{c}",
+                                value: "{c}",
+                                selected: "{c == selected}",
+                                "{column_name}"
+                            })
+                        })
+                    });
+                    let row_count = parsed.rows.len();
+                    rsx!(cx, div {
+                        label { "Front: " column_select(mapping.front, |m, front| ColumnMapping { front, ..m }) }
+                        label { " Back: " column_select(mapping.back, |m, back| ColumnMapping { back, ..m }) }
+                        table {
+                            class: "import-preview",
+                            tr {
+                                (0..columns).map(|c| {
+                                    let column_name = parsed.column_name(c);
+                                    let side = if c == mapping.front {
+                                        " (front)"
+                                    } else if c == mapping.back {
+                                        " (back)"
+                                    } else {
+                                        ""
+                                    };
+                                    rsx!(cx, th { key: "{c}", "{column_name}{side}" })
+                                })
+                            }
+                            parsed.rows.iter().take(PREVIEW_ROWS).enumerate().map(|(i, row)| rsx!(cx, tr {
+                                key: "{i}",
+                                row.iter().enumerate().map(|(c, field)| rsx!(cx, td { key: "{c}", "{field}" }))
+                            }))
+                        }
+                        (row_count > PREVIEW_ROWS).then(|| rsx!(cx, p {"...and {row_count - PREVIEW_ROWS} more rows"}))
+                    })
+                }
+            };
+            rsx!(cx, div {
+                div {
+                    label {
+                        "Separator "
+                        select {
+                            oninput: move |e| {
+                                match SEPARATORS.iter().find(|(name, _)| *name == e.value) {
+                                    Some((_, separator)) => {
+                                        set_custom_separator(false);
+                                        set_layout(Layout { separator: *separator, ..*layout });
+                                    }
+                                    None => set_custom_separator(true),
+                                }
+                            },
+                            SEPARATORS.iter().map(|(name, separator)| rsx!(cx, option {
+                                key: "{name}",
+                                value: "{name}",
+                                selected: "{!custom_separator && *separator == layout.separator}",
+                                "{name}"
+                            }))
+                            option { value: "Other", selected: "{custom_separator}", "Other" }
+                        }
+                    }
+                    custom_separator.then(|| rsx!(cx, input {
+                        maxlength: "1",
+                        size: "1",
+                        oninput: move |e| {
+                            // The separator has to be a single byte
+                            if let [separator] = e.value.as_bytes() {
+                                set_layout(Layout { separator: *separator, ..*layout });
+                            }
+                        },
+                    }))
+                    label {
+                        input {
+                            "type": "checkbox",
+                            checked: "{layout.has_header}",
+                            oninput: move |_| set_layout(Layout { has_header: !layout.has_header, ..*layout }),
+                        }
+                        "First row is a header"
+                    }
+                }
+                preview
+                input {
+                    "type": "input",
+                    placeholder: "Set name",
+                    value: "{name}",
+                    oninput: move |e| set_name(e.value.clone()),
+                }
+                button {
+                    "type": "button",
+                    disabled: "{!can_import}",
+                    onclick: move |_| {
+                        let source = source.read();
+                        let table = match &*source {
+                            Some(Source::Delimited { text, .. }) => match delimited::parse(text, *layout) {
+                                Ok(table) => table,
+                                Err(_) => return,
+                            },
+                            _ => return,
+                        };
+                        let name = name.trim().to_owned();
+                        if name.is_empty() {
+                            set_message(Some("Please give the set a name".into()));
+                        } else if user_data.borrow().get().name_taken(&name, None) {
+                            set_message(Some("Please use a unique name".into()));
+                        } else {
+                            let set = delimited::to_set(name, &table, *mapping);
+                            if set.flashcards.is_empty() {
+                                set_message(Some("There are no cards in those columns".into()));
+                            } else {
+                                user_data.borrow_mut().replace_set(set);
+                                set_page(CurrentPage::Flashcards);
+                            }
+                        }
+                    },
+                    "Import"
+                }
+            })
+        }
+    };
+
+    rsx!(cx, div {
+        class: "center-div",
+        h1 {"Import"}
+        button {
+            "type": "button",
+            onclick: choose_file,
+            "Choose file"
+        }
+        picked.map(|path| rsx!(cx, p {"{path}"}))
+        form
+        message.as_ref().map(|m| rsx!(cx, p { class: "warning", "{m}" }))
+    })
+}
@@ -1,20 +1,39 @@
 #![allow(non_snake_case)]
 #![deny(unsafe_code)]
 #![warn(clippy::correctness, clippy::suspicious, clippy::style, clippy::complexity, clippy::perf, clippy::nursery)]
-#![feature(once_cell)]
 
+mod anki;
+mod answer;
+mod archive;
+mod autosave;
 mod data;
+mod delimited;
+mod escape;
 mod flashcards;
+mod import;
+mod markdown;
+mod media;
+mod migrate;
 mod note_input;
+mod persist;
+mod profile;
+mod recovery;
+mod rich_text;
+mod schedule;
+mod session;
+mod storage;
 mod study;
 
-use std::cell::RefCell;
+use std::{cell::RefCell, time::Duration};
 
-use data::{UserData, UserDataAccessor};
-use dioxus::desktop::tao::window::Icon;
+use data::{SetId, UserDataAccessor};
+use dioxus::desktop::tao::{
+    event::{Event, WindowEvent},
+    window::Icon,
+};
 use dioxus::fermi::{use_read, use_set, Atom};
 use dioxus::prelude::*;
-use log::error;
+use log::{error, warn};
 use simplelog::*;
 use study::FlashcardTesterProps;
 
@@ -22,12 +41,12 @@ use study::FlashcardTesterProps;
 static CURRENT_PAGE: Atom<CurrentPage> = |_| CurrentPage::HomePage;
 /// An atom containing the global user data
 static USER_DATA: Atom<RefCell<UserDataAccessor>> = |_| {
-    RefCell::new(UserDataAccessor::new(UserData::load().unwrap_or_else(
-        |e| {
-            error!("Could not load existing data: {}", e);
-            UserData::default()
-        },
-    )))
+    let accessor = profile::open(&profile::current());
+    if let Some(failure) = accessor.recovery() {
+        // Don't fall back to defaults - they'd be saved over the user's data
+        error!("Could not load existing data: {}", failure.error);
+    }
+    RefCell::new(accessor)
 };
 
 /// Represents current page states - matched on in the main app
@@ -35,9 +54,16 @@ static USER_DATA: Atom<RefCell<UserDataAccessor>> = |_| {
 enum CurrentPage {
     HomePage,
     Flashcards,
-    NoteInput,
+    /// Editing a set - a new one, or the set with this id
+    NoteInput(Option<SetId>),
     StudySetup,
-    FlashcardTester (FlashcardTesterProps)
+    /// Importing a set from a file
+    Import,
+    /// Backing up all the data, or restoring it
+    Backup,
+    FlashcardTester (FlashcardTesterProps),
+    /// Shown when the user data couldn't be loaded
+    Recovery,
 }
 
 #[derive(Props, PartialEq)]
@@ -52,11 +78,15 @@ struct PageLinkProps {
 /// A page link - a button to open another current page
 fn PageLink(cx: Scope<PageLinkProps>) -> Element {
     let set_page = use_set(&cx, CURRENT_PAGE);
+    let user_data = use_read(&cx, USER_DATA);
+    let studying_since = use_read(&cx, study::STUDYING_SINCE);
     cx.render(rsx! {
         button {
             "type": "button",
             class: "{cx.props.class}",
             onclick: move |_| {
+                // Leaving a study session part way through still counts the time spent in it
+                study::record_unfinished_session(studying_since, user_data);
                 set_page(cx.props.redirect.clone());
             },
             "{cx.props.name}"
@@ -80,6 +110,35 @@ fn HomePage(cx: Scope) -> Element {
                 name: "Flashcards",
                 redirect: CurrentPage::Flashcards
             }
+            PageLink {
+                class: "pagelink",
+                name: "Back up and restore",
+                redirect: CurrentPage::Backup
+            }
+            profile::ProfileSwitcher {}
+        }
+    })
+}
+
+/// Warns that changes couldn't be saved, with a button to try again
+fn SaveStatus(cx: Scope) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    // Saving happens in the background, so check every second for failures
+    let update = cx.schedule_update();
+    use_future(&cx, || async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            update();
+        }
+    });
+    let error = user_data.borrow().save_error()?;
+    rsx!(cx, div {
+        class: "save-error",
+        p { class: "warning", "Your changes couldn't be saved: {error}" }
+        button {
+            "type": "button",
+            onclick: move |_| user_data.borrow().flush(),
+            "Try again"
         }
     })
 }
@@ -112,6 +171,11 @@ fn Font(cx: Scope<FontProps>) -> Element {
 /// Main app component - renders current page + basic frame
 fn App(cx: Scope) -> Element {
     let read_page = use_read(&cx, CURRENT_PAGE);
+    let set_page = use_set(&cx, CURRENT_PAGE);
+    // Stay on the recovery page until the user has decided what to do with their data
+    if use_read(&cx, USER_DATA).borrow().recovery().is_some() && *read_page != CurrentPage::Recovery {
+        set_page(CurrentPage::Recovery);
+    }
     rsx! (cx, div {
         style {[include_str!("style.css")]},
         Font {link: "https://fonts.googleapis.com/css2?family=Source+Sans+Pro:wght@300&display=swap"},
@@ -119,10 +183,14 @@ fn App(cx: Scope) -> Element {
         match read_page {
             CurrentPage::HomePage => rsx!(cx, HomePage {}),
             CurrentPage::Flashcards => rsx!(cx, flashcards::Flashcards {}),
-            CurrentPage::NoteInput => rsx!(cx, note_input::InputFlashcards {}),
+            CurrentPage::NoteInput(edit) => rsx!(cx, note_input::InputFlashcards {edit: *edit}),
             CurrentPage::StudySetup => rsx!(cx, study::Study {}),
-            CurrentPage::FlashcardTester(props) => rsx!(cx, study::FlashcardTester {..props.clone()})
+            CurrentPage::Import => rsx!(cx, import::Import {}),
+            CurrentPage::Backup => rsx!(cx, archive::Backup {}),
+            CurrentPage::FlashcardTester(props) => rsx!(cx, study::FlashcardTester {..props.clone()}),
+            CurrentPage::Recovery => rsx!(cx, recovery::Recovery {})
         },
+        SaveStatus {},
         div {
             PageLink {
                 class: "home-button",
@@ -133,9 +201,30 @@ fn App(cx: Scope) -> Element {
     })
 }
 
+/// Reads `--data-dir <folder>` and `--profile <name>` from the command line.
+/// They're passed on as environment variables, which can also be set directly.
+fn read_args() {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let var = match arg.as_str() {
+            "--data-dir" => profile::DATA_DIR_VAR,
+            "--profile" => profile::PROFILE_VAR,
+            _ => {
+                warn!("Unknown argument {}", arg);
+                continue;
+            }
+        };
+        match args.next() {
+            Some(value) => std::env::set_var(var, value),
+            None => warn!("{} needs a value", arg),
+        }
+    }
+}
+
 fn main() {
     // Initialising log
     SimpleLogger::init(LevelFilter::Info, Config::default()).unwrap();
+    read_args();
     // Icon
     let icon_bytes = include_bytes!("../assets/magistrax-logos_black.png");
     let decoder = png::Decoder::new(icon_bytes as &[u8]);
@@ -150,5 +239,13 @@ fn main() {
             w.with_title("Magistrax")
                 .with_maximized(true)
         )
-        .with_icon(Icon::from_rgba(buf, icon.width, icon.height).unwrap()));
+        .with_icon(Icon::from_rgba(buf, icon.width, icon.height).unwrap())
+        // Pictures dropped on the window are added to the card being edited
+        .with_file_drop_handler(|_, event| media::file_drop(event))
+        // Closing the window ends the process without dropping the user data, so save it first
+        .with_event_handler(|event, _| {
+            if let Event::WindowEvent { event: WindowEvent::CloseRequested | WindowEvent::Destroyed, .. } = event {
+                autosave::flush_all();
+            }
+        }));
 }
@@ -0,0 +1,376 @@
+//! A plain-text deck format that reads well as Markdown and diffs well in version control:
+//!
+//! ```text
+//! ## French
+//! <!-- study: both -->
+//!
+//! Je suis :: I am
+//! Rouge {#ff0000} :: Red {#ff0000}
+//!
+//! Q: A question over
+//! two lines
+//! A: And its answer
+//! ```
+//!
+//! Each `## heading` starts a set. Cards are `front :: back` lines, or `Q:`/`A:` blocks for text
+//! over several lines, separated by blank lines. Sides are written in the markup of [`crate::rich_text`],
+//! followed by their colour in braces.
+//! Inside a block, lines that would look like markup start with `\`, and a lone `\` is a blank line.
+//! A side's pictures and sounds follow it as `![](media/name)` and `[sound](media/name)`, with the files
+//! kept in a `media` folder next to the deck.
+use std::io;
+
+use crate::{
+    data::{Color, FlashcardSet, RichText},
+    schedule::StudyDirection,
+};
+
+/// Separates the sides of a one-line card
+const SEPARATOR: &str = " :: ";
+const QUESTION: &str = "Q:";
+const ANSWER: &str = "A:";
+const HEADING: &str = "## ";
+/// Written before the study direction of a set, in a comment so it's hidden when rendered
+const DIRECTION_PREFIX: &str = "<!-- study: ";
+const DIRECTION_SUFFIX: &str = " -->";
+/// Folder next to a deck its pictures and sounds are kept in
+pub const MEDIA_FOLDER: &str = "media";
+
+/// Splits a colour annotation off the end of a side - `text {colour}`
+fn split_color(side: &str) -> RichText {
+    let annotated = side.strip_suffix('}').and_then(|rest| {
+        let (text, color) = rest.rsplit_once(" {").or_else(|| rest.strip_prefix('{').map(|c| ("", c)))?;
+        Some((text, Color::parse(color)?))
+    });
+    match annotated {
+        Some((text, color)) => RichText::from_markup(text, color),
+        None => RichText::from_markup(side, Color::black()),
+    }
+}
+
+/// Splits the pictures and sounds off the end of a side, returning the rest of it and their paths
+fn split_media(side: &str) -> (&str, Vec<String>, Vec<String>) {
+    let mut rest = side.trim_end();
+    let (mut images, mut audio) = (vec![], vec![]);
+    while let Some((before, path)) = rest.strip_suffix(')').and_then(|inner| inner.rsplit_once("](")) {
+        if path.is_empty() || path.contains(|c: char| c.is_whitespace() || c == '(' || c == ')') {
+            break;
+        }
+        let (before, paths) = if let Some(before) = before.strip_suffix("![") {
+            (before, &mut images)
+        } else if let Some(before) = before.strip_suffix("[sound") {
+            (before, &mut audio)
+        } else {
+            break;
+        };
+        paths.push(path.to_owned());
+        rest = before.trim_end();
+    }
+    images.reverse();
+    audio.reverse();
+    (rest, images, audio)
+}
+
+/// Reads a side - its text and colour, then any pictures and sounds, by their paths
+fn read_side(side: &str) -> RichText {
+    let (rest, images, audio) = split_media(side);
+    RichText {
+        images,
+        audio,
+        ..split_color(rest)
+    }
+}
+
+/// Writes a side with its colour annotation, if it needs one, followed by its pictures and sounds
+fn write_side(text: &RichText) -> String {
+    let markup = text.markup();
+    // Text that ends like an annotation or a picture gets an explicit colour, so it isn't read as one.
+    // So does text with pictures or sounds after it, so they're kept apart.
+    let mut side = if text.color == Color::black() && read_side(&markup) == *text {
+        markup
+    } else if markup.is_empty() {
+        format!("{{{}}}", text.color)
+    } else {
+        format!("{} {{{}}}", markup, text.color)
+    };
+    for image in &text.images {
+        side.push_str(&format!(" ![]({}/{})", MEDIA_FOLDER, image));
+    }
+    for sound in &text.audio {
+        side.push_str(&format!(" [sound]({}/{})", MEDIA_FOLDER, sound));
+    }
+    side
+}
+
+/// Whether a line inside a `Q:`/`A:` block needs a `\` in front so it isn't read as markup
+fn needs_escape(line: &str) -> bool {
+    line.is_empty()
+        || line.starts_with('\\')
+        || line.starts_with(QUESTION)
+        || line.starts_with(ANSWER)
+        || line.starts_with('#')
+        || line.trim().is_empty()
+}
+
+/// Whether a card can be written on one line - `front :: back`
+fn fits_on_line(front: &str, back: &str) -> bool {
+    let plain = |side: &str| !side.contains('\n') && side.trim() == side && !side.is_empty();
+    plain(front)
+        && plain(back)
+        && !front.contains("::")
+        && !front.starts_with(['#', '\\', '<'])
+        && !front.starts_with(QUESTION)
+        && !front.starts_with(ANSWER)
+}
+
+/// Writes a side as the lines of a `Q:`/`A:` block
+fn write_block(out: &mut String, marker: &str, side: &str) {
+    for (i, line) in side.split('\n').enumerate() {
+        if i == 0 {
+            out.push_str(marker);
+            if !line.is_empty() {
+                out.push(' ');
+                out.push_str(line);
+            }
+        } else if needs_escape(line) {
+            out.push('\\');
+            out.push_str(line);
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+}
+
+/// Writes sets in the text format
+pub fn write(sets: &[FlashcardSet]) -> String {
+    let mut out = String::new();
+    for set in sets {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(HEADING);
+        out.push_str(&set.name);
+        out.push('\n');
+        if set.direction != StudyDirection::default() {
+            out.push_str(&format!("{}{}{}\n", DIRECTION_PREFIX, set.direction.name(), DIRECTION_SUFFIX));
+        }
+        for card in &set.flashcards {
+            out.push('\n');
+            let (front, back) = (write_side(&card.front), write_side(&card.back));
+            if fits_on_line(&front, &back) {
+                out.push_str(&format!("{}{}{}\n", front, SEPARATOR, back));
+            } else {
+                write_block(&mut out, QUESTION, &front);
+                write_block(&mut out, ANSWER, &back);
+            }
+        }
+    }
+    out
+}
+
+/// Which part of a `Q:`/`A:` block is being read
+enum Block {
+    Question(Vec<String>),
+    Answer(Vec<String>, Vec<String>),
+}
+
+/// Reads sets from the text format. Cards before the first heading go in a set called `default_name`.
+pub fn parse(text: &str, default_name: &str) -> io::Result<Vec<FlashcardSet>> {
+    let error = |line: usize, message: &str| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line + 1, message))
+    };
+    let mut sets: Vec<FlashcardSet> = vec![];
+    let mut block: Option<Block> = None;
+    let add = |sets: &mut Vec<FlashcardSet>, front: &str, back: &str| {
+        if sets.is_empty() {
+            sets.push(FlashcardSet::new(default_name.into()));
+        }
+        sets.last_mut().unwrap().add(read_side(front), read_side(back));
+    };
+    for (number, line) in text.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        // Carry on a block until a blank line
+        if let Some(current) = block.take() {
+            if !line.trim().is_empty() {
+                let content = line.strip_prefix('\\');
+                block = Some(match (current, content) {
+                    (Block::Question(question), None) if line.starts_with(ANSWER) => {
+                        let rest = &line[ANSWER.len()..];
+                        Block::Answer(question, vec![rest.strip_prefix(' ').unwrap_or(rest).into()])
+                    }
+                    (Block::Question(mut question), _) => {
+                        question.push(content.unwrap_or(line).into());
+                        Block::Question(question)
+                    }
+                    (Block::Answer(question, mut answer), _) => {
+                        answer.push(content.unwrap_or(line).into());
+                        Block::Answer(question, answer)
+                    }
+                });
+                continue;
+            }
+            match current {
+                Block::Answer(question, answer) => add(&mut sets, &question.join("\n"), &answer.join("\n")),
+                Block::Question(_) => return Err(error(number, "expected an `A:` line to finish the card")),
+            }
+            continue;
+        }
+        if line.trim().is_empty() || (line.starts_with("# ") && sets.is_empty()) {
+            // Blank lines, and a title for the whole file
+        } else if let Some(name) = line.strip_prefix(HEADING) {
+            sets.push(FlashcardSet::new(name.trim().into()));
+        } else if let Some(direction) = line
+            .strip_prefix(DIRECTION_PREFIX)
+            .and_then(|rest| rest.strip_suffix(DIRECTION_SUFFIX))
+        {
+            let set = sets.last_mut().ok_or_else(|| error(number, "a study direction needs a `## set` above it"))?;
+            set.direction = StudyDirection::from_name(direction.trim())
+                .ok_or_else(|| error(number, "the study direction should be forward, reverse or both"))?;
+        } else if let Some(question) = line.strip_prefix(QUESTION) {
+            block = Some(Block::Question(vec![question.strip_prefix(' ').unwrap_or(question).into()]));
+        } else if let Some((front, back)) = line.split_once(SEPARATOR) {
+            add(&mut sets, front.trim(), back.trim());
+        } else {
+            return Err(error(number, "expected `front :: back`, a `Q:` line or a `## set` heading"));
+        }
+    }
+    match block {
+        Some(Block::Answer(question, answer)) => add(&mut sets, &question.join("\n"), &answer.join("\n")),
+        Some(Block::Question(_)) => return Err(error(text.lines().count(), "expected an `A:` line to finish the card")),
+        None => {}
+    }
+    Ok(sets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(name: &str, cards: &[(RichText, RichText)]) -> FlashcardSet {
+        let mut set = FlashcardSet::new(name.into());
+        for (front, back) in cards {
+            set.add(front.clone(), back.clone());
+        }
+        set
+    }
+
+    fn plain(text: &str) -> RichText {
+        RichText::plaintext(text.into())
+    }
+
+    fn colored(text: &str, color: &str) -> RichText {
+        RichText {
+            text: text.into(),
+            color: Color::parse(color).unwrap(),
+            ..RichText::empty()
+        }
+    }
+
+    /// A set's name, direction and the sides of its cards
+    type Sides = (String, StudyDirection, Vec<(RichText, RichText)>);
+
+    /// The sides of every card, to compare sets ignoring ids
+    fn sides(sets: &[FlashcardSet]) -> Vec<Sides> {
+        sets.iter()
+            .map(|s| {
+                let cards = s.flashcards.iter().map(|c| (c.front.clone(), c.back.clone())).collect();
+                (s.name.clone(), s.direction, cards)
+            })
+            .collect()
+    }
+
+    fn assert_round_trip(sets: &[FlashcardSet]) {
+        let text = write(sets);
+        let parsed = parse(&text, "Imported").unwrap_or_else(|e| panic!("{}\n{}", e, text));
+        assert_eq!(sides(&parsed), sides(sets), "\n{}", text);
+    }
+
+    #[test]
+    fn reads_both_card_styles() {
+        let text = "# My course\n\n## French\n<!-- study: both -->\n\nJe suis :: I am\nRouge {#ff0000} :: Red\n\nQ: Two\nlines\nA: Answer\n\n## German\nIch bin :: I am\n";
+        let sets = parse(text, "Imported").unwrap();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].name, "French");
+        assert_eq!(sets[0].direction, StudyDirection::Both);
+        assert_eq!(sets[0].flashcards[1].front, colored("Rouge", "#ff0000"));
+        assert_eq!(sets[0].flashcards[2].front, plain("Two\nlines"));
+        assert_eq!(sets[0].flashcards[2].back, plain("Answer"));
+        assert_eq!(sets[1].flashcards[0].back, plain("I am"));
+    }
+
+    #[test]
+    fn cards_before_a_heading_use_the_default_name() {
+        let sets = parse("one :: two\n", "Vocab").unwrap();
+        assert_eq!(sets[0].name, "Vocab");
+        assert_eq!(sets[0].flashcards.len(), 1);
+    }
+
+    #[test]
+    fn reports_the_bad_line() {
+        let error = parse("## Set\n\nno separator here\n", "Imported").unwrap_err();
+        assert!(error.to_string().starts_with("line 3"), "{}", error);
+        assert!(parse("## Set\nQ: unanswered\n", "Imported").is_err());
+    }
+
+    #[test]
+    fn round_trips_simple_sets() {
+        let mut reversed = set("German", &[(plain("Ich bin"), plain("I am"))]);
+        reversed.direction = StudyDirection::Reverse;
+        assert_round_trip(&[
+            set("French", &[(plain("Je suis"), plain("I am")), (colored("Rouge", "#ff0000"), colored("Red", "red"))]),
+            reversed,
+        ]);
+    }
+
+    #[test]
+    fn round_trips_awkward_text() {
+        assert_round_trip(&[set(
+            "Awkward",
+            &[
+                (plain("line one\nline two"), plain("a :: b")),
+                (plain("a :: b"), plain("")),
+                (plain("Q: looks like a question"), plain("A: and an answer")),
+                (plain("ends in {braces}"), plain("{}")),
+                (plain("blank\n\nline"), plain("\\backslash\n# hash\nQ: inside")),
+                (plain("  spaces  "), colored("", "#00ff00")),
+                (plain("## not a heading"), plain("<!-- study: both -->")),
+                (colored("multi\nline", "rgb(1, 2, 3)"), plain("x")),
+                (plain("2*3 = 6 and a_b"), plain("{red|not colour}")),
+            ],
+        )]);
+    }
+
+    #[test]
+    fn writes_media_after_the_text() {
+        let front = RichText { images: vec!["cat.png".into()], ..plain("Cat") };
+        let back = RichText { audio: vec!["miaou.mp3".into(), "purr.ogg".into()], ..plain("") };
+        let text = write(&[set("Animals", &[(front, back), (plain("see ![](x.png)"), plain("[sound](y)"))])]);
+        assert!(text.contains("![](media/cat.png)"), "{}", text);
+        let parsed = parse(&text, "Imported").unwrap_or_else(|e| panic!("{}\n{}", e, text));
+        let cards = &parsed[0].flashcards;
+        assert_eq!(cards[0].front, RichText { images: vec!["media/cat.png".into()], ..plain("Cat") });
+        assert_eq!(cards[0].back.text, "");
+        assert_eq!(cards[0].back.audio, vec!["media/miaou.mp3".to_owned(), "media/purr.ogg".to_owned()]);
+        // Text that only looks like pictures and sounds stays text
+        assert_eq!(cards[1].front, plain("see ![](x.png)"));
+        assert_eq!(cards[1].back, plain("[sound](y)"));
+    }
+
+    #[test]
+    fn round_trips_formatting() {
+        let formatted = |markup: &str| RichText::from_markup(markup, Color::black());
+        assert_round_trip(&[set(
+            "Chemistry",
+            &[
+                (formatted("H_{2}O"), formatted("**Water** is {blue|wet}")),
+                (formatted("Steps:\n1. *mix*\n2. heat"), colored("text {with} braces", "#ff0000")),
+                (formatted("{red|all red}"), formatted("- a\n- b")),
+            ],
+        )]);
+        let sets = parse("CO_{2} :: **carbon** dioxide {#333333}\n", "Imported").unwrap();
+        assert_eq!(sets[0].flashcards[0].front.text, "CO2");
+        assert_eq!(sets[0].flashcards[0].back.text, "carbon dioxide");
+        assert_eq!(sets[0].flashcards[0].back.color, Color::parse("#333333").unwrap());
+    }
+}
@@ -0,0 +1,428 @@
+//! The media store - images and sounds kept in a folder next to the data file (see [`storage::media_dir`]).
+//!
+//! Files are named by a hash of their contents, so a file added twice is only kept once, and a
+//! name always means the same file. Files no card uses any more, and no backup of the data either, are
+//! deleted when the data is opened.
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs, io, mem,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Mutex,
+};
+
+use dioxus::{desktop::wry::webview::FileDropEvent, fermi::use_read, prelude::*};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    data::UserData,
+    persist,
+    storage::{self, Storage},
+    USER_DATA,
+};
+
+/// Extensions of the images that can be added
+pub const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "webp", "svg"];
+/// Extensions of the sounds that can be added
+pub const AUDIO_EXTENSIONS: [&str; 4] = ["mp3", "m4a", "ogg", "wav"];
+/// Biggest file that can be added, in bytes - everything is read into memory to be shown
+const MAX_SIZE: u64 = 20 * 1024 * 1024;
+/// Most bytes of data URIs kept in memory - the least recently shown files are read again beyond this
+const MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Files dropped on the window, waiting for the editor to add them to a card
+static DROPPED_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Files already read, as data URIs - a name always means the same file, so they never go stale
+    static URI_CACHE: RefCell<UriCache> = RefCell::new(UriCache::new(MAX_CACHED_BYTES));
+}
+
+/// Data URIs of recently shown files, up to a total size
+struct UriCache {
+    /// Each file's URI (`None` if it couldn't be read), with when it was last used
+    uris: HashMap<String, (u64, Option<Rc<str>>)>,
+    /// Counts up on every use
+    clock: u64,
+    /// Bytes held
+    size: usize,
+    limit: usize,
+}
+impl UriCache {
+    fn new(limit: usize) -> Self {
+        Self {
+            uris: HashMap::new(),
+            clock: 0,
+            size: 0,
+            limit,
+        }
+    }
+    /// Bytes an entry takes up
+    fn cost(name: &str, uri: &Option<Rc<str>>) -> usize {
+        name.len() + uri.as_ref().map_or(0, |uri| uri.len())
+    }
+    /// The URI of `name`, reading it with `read` if it isn't kept
+    fn get(&mut self, name: &str, read: impl FnOnce() -> Option<Rc<str>>) -> Option<Rc<str>> {
+        self.clock += 1;
+        if let Some((used, uri)) = self.uris.get_mut(name) {
+            *used = self.clock;
+            return uri.clone();
+        }
+        let uri = read();
+        self.size += Self::cost(name, &uri);
+        self.uris.insert(name.to_owned(), (self.clock, uri.clone()));
+        // The file just read is kept, however big
+        while self.size > self.limit && self.uris.len() > 1 {
+            let oldest = self.uris.iter().min_by_key(|(_, (used, _))| *used).map(|(name, _)| name.clone());
+            if let Some((name, (_, old))) = oldest.and_then(|name| self.uris.remove_entry(&name)) {
+                self.size -= Self::cost(&name, &old);
+            }
+        }
+        uri
+    }
+}
+
+/// What a media file holds
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MediaKind {
+    Image,
+    Audio,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Whether a file name is safe to use in the media folder
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Whether a file name is one the store gives files - a hash and an extension
+fn stored_name(name: &str) -> bool {
+    match name.split_once('.') {
+        Some((hash, extension)) => {
+            hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) && valid_name(extension)
+        }
+        None => false,
+    }
+}
+
+/// The MIME type of a media file, from its extension
+fn mime_type(name: &str) -> Option<&'static str> {
+    Some(match name.rsplit_once('.')?.1 {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => return None,
+    })
+}
+
+/// Adds a file to the store, returning its name there
+pub fn store(media_dir: &Path, bytes: &[u8], extension: &str) -> io::Result<String> {
+    let name = format!("{:x}.{}", Sha256::digest(bytes), extension.to_ascii_lowercase());
+    if !valid_name(&name) {
+        return Err(invalid(format!("{:?} isn't a usable file extension", extension)));
+    }
+    let path = media_dir.join(&name);
+    if !path.exists() {
+        fs::create_dir_all(media_dir)?;
+        persist::write_atomic(&path, bytes)?;
+    }
+    Ok(name)
+}
+
+/// Adds an image or sound file to the store, returning what it is and its name there
+pub fn add_file(media_dir: &Path, path: &Path) -> io::Result<(MediaKind, String)> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).unwrap_or_default();
+    let kind = if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        MediaKind::Image
+    } else if AUDIO_EXTENSIONS.contains(&extension.as_str()) {
+        MediaKind::Audio
+    } else {
+        return Err(invalid("it isn't a picture or a sound"));
+    };
+    if fs::metadata(path)?.len() > MAX_SIZE {
+        return Err(invalid(format!("it's bigger than {} MB", MAX_SIZE / 1024 / 1024)));
+    }
+    Ok((kind, store(media_dir, &fs::read(path)?, &extension)?))
+}
+
+/// Adds an image from its pixels (8-bit RGBA, as on the clipboard) to the store as a PNG
+pub fn add_pixels(media_dir: &Path, width: u32, height: u32, rgba: &[u8]) -> io::Result<String> {
+    let mut bytes = vec![];
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgba)?;
+    store(media_dir, &bytes, "png")
+}
+
+/// A media file as a `data:` URI, which the webview can show without being given access to files
+pub fn data_uri(media_dir: &Path, name: &str) -> io::Result<String> {
+    let mime = mime_type(name).filter(|_| valid_name(name)).ok_or_else(|| invalid("unknown kind of file"))?;
+    let bytes = fs::read(media_dir.join(name))?;
+    Ok(format!("data:{};base64,{}", mime, base64::encode(bytes)))
+}
+
+/// A media file as a `data:` URI, kept after it's read while it's still being shown, or `None` if it couldn't be read
+pub fn cached_uri(media_dir: &Path, name: &str) -> Option<Rc<str>> {
+    URI_CACHE.with(|cache| {
+        cache.borrow_mut().get(name, || match data_uri(media_dir, name) {
+            Ok(uri) => Some(uri.into()),
+            Err(e) => {
+                warn!("Could not read media file {}: {}", name, e);
+                None
+            }
+        })
+    })
+}
+
+/// Names of the media files the cards in `data` use
+fn used_by(data: &UserData) -> HashSet<&str> {
+    data.sets
+        .iter()
+        .flat_map(|set| &set.flashcards)
+        .flat_map(|card| card.front.media().chain(card.back.media()))
+        .collect()
+}
+
+/// Names of the files in the store that `data` doesn't use
+fn unused(media_dir: &Path, data: &UserData) -> io::Result<Vec<String>> {
+    let used = used_by(data);
+    let entries = match fs::read_dir(media_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut unused = vec![];
+    for entry in entries {
+        let entry = entry?;
+        // Leave alone anything the store didn't put there
+        let name = match entry.file_name().into_string() {
+            Ok(name) if stored_name(&name) => name,
+            _ => continue,
+        };
+        if entry.file_type()?.is_file() && !used.contains(name.as_str()) {
+            unused.push(name);
+        }
+    }
+    Ok(unused)
+}
+
+/// Deletes the files in the store that no card in `data` uses, returning how many were deleted.
+/// Files used by a backup of the data, or by a damaged copy that was moved aside, are kept, so
+/// restoring or rescuing one brings its pictures and sounds back too.
+pub fn collect_garbage(storage: &dyn Storage, data: &UserData) -> io::Result<usize> {
+    let media_dir = storage::media_dir(storage.path());
+    let mut unused = unused(&media_dir, data)?;
+    for (_, backup) in persist::backups(storage.path()) {
+        if unused.is_empty() {
+            break;
+        }
+        match storage.read_backup(&backup) {
+            Ok(old) => {
+                let used = used_by(&old);
+                unused.retain(|name| !used.contains(name.as_str()));
+            }
+            // Restoring it couldn't use the files either
+            Err(e) => warn!("Could not read backup {} for the media it uses: {}", backup.display(), e),
+        }
+    }
+    for damaged in persist::quarantined(storage.path()) {
+        if unused.is_empty() {
+            break;
+        }
+        let rescued = UserData::salvage(&damaged).map(|s| s.data).or_else(|_| storage.read_backup(&damaged));
+        match rescued {
+            Ok(old) => {
+                let used = used_by(&old);
+                unused.retain(|name| !used.contains(name.as_str()));
+            }
+            Err(e) => {
+                // There's no telling what it uses, and the user may still want it
+                info!("Keeping all media, as {} can't be read: {}", damaged.display(), e);
+                return Ok(0);
+            }
+        }
+    }
+    for name in &unused {
+        fs::remove_file(media_dir.join(name))?;
+    }
+    if !unused.is_empty() {
+        info!("Deleted {} unused media files", unused.len());
+    }
+    Ok(unused.len())
+}
+
+/// Handles files dragged onto the window - dropped ones are kept for the editor to pick up
+pub fn file_drop(event: FileDropEvent) -> bool {
+    if let FileDropEvent::Dropped(paths) = event {
+        DROPPED_FILES.lock().unwrap().extend(paths);
+    }
+    // Stop the webview opening the files itself
+    true
+}
+
+/// Whether there are dropped files waiting
+pub fn files_dropped() -> bool {
+    !DROPPED_FILES.lock().unwrap().is_empty()
+}
+
+/// Takes the files dropped on the window since last time
+pub fn take_dropped() -> Vec<PathBuf> {
+    mem::take(&mut *DROPPED_FILES.lock().unwrap())
+}
+
+#[derive(Props, PartialEq)]
+pub struct ImagesProps {
+    /// Names of the images in the media store
+    images: Vec<String>,
+}
+/// Shows images from the media store
+pub fn Images(cx: Scope<ImagesProps>) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    if cx.props.images.is_empty() {
+        return None;
+    }
+    let media_dir = storage::media_dir(user_data.borrow().storage().path());
+    let images = cx.props.images.iter().map(|name| match cached_uri(&media_dir, name) {
+        Some(uri) => rsx!(cx, img { key: "{name}", src: "{uri}" }),
+        None => rsx!(cx, p { key: "{name}", class: "warning", "This picture is missing" }),
+    });
+    rsx!(cx, div {
+        class: "card-images",
+        images
+    })
+}
+
+#[derive(Props, PartialEq)]
+pub struct SoundsProps {
+    /// Names of the sounds in the media store
+    clips: Vec<String>,
+    /// Whether to start playing as soon as they're shown
+    autoplay: bool,
+}
+/// Players for sounds from the media store
+pub fn Sounds(cx: Scope<SoundsProps>) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    if cx.props.clips.is_empty() {
+        return None;
+    }
+    let media_dir = storage::media_dir(user_data.borrow().storage().path());
+    let clips = cx.props.clips.iter().enumerate().map(|(i, name)| match cached_uri(&media_dir, name) {
+        Some(uri) => {
+            // Only the first plays by itself, so they don't play over each other
+            let autoplay = cx.props.autoplay && i == 0;
+            rsx!(cx, audio { key: "{name}", controls: "true", autoplay: "{autoplay}", src: "{uri}" })
+        }
+        None => rsx!(cx, p { key: "{name}", class: "warning", "This sound is missing" }),
+    });
+    rsx!(cx, div {
+        class: "card-sounds",
+        clips
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{FlashcardSet, RichText},
+        storage::JsonStorage,
+    };
+
+    #[test]
+    fn stores_each_image_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = store(dir.path(), b"a picture", "PNG").unwrap();
+        assert!(stored_name(&name), "{}", name);
+        assert!(name.ends_with(".png"));
+        assert_eq!(store(dir.path(), b"a picture", "png").unwrap(), name);
+        assert_ne!(store(dir.path(), b"another picture", "png").unwrap(), name);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        let uri = data_uri(dir.path(), &name).unwrap();
+        assert_eq!(uri, format!("data:image/png;base64,{}", base64::encode(b"a picture")));
+    }
+
+    /// Data with one card using these images and sounds
+    fn data_using(images: &[&String], audio: &[&String]) -> UserData {
+        let mut data = UserData::empty();
+        let mut set = FlashcardSet::new("Flags".into());
+        let front = RichText { images: images.iter().map(|&n| n.clone()).collect(), ..RichText::empty() };
+        let back = RichText { audio: audio.iter().map(|&n| n.clone()).collect(), ..RichText::plaintext("France".into()) };
+        set.add(front, back);
+        data.add_set(set);
+        data
+    }
+
+    #[test]
+    fn forgets_the_least_recently_used_uris() {
+        let mut cache = UriCache::new(30);
+        let uri = |text: &str| Some(Rc::from(text));
+        cache.get("a", || uri("0123456789"));
+        cache.get("b", || uri("0123456789"));
+        // Using a keeps it, so b is dropped to make room for c
+        assert_eq!(cache.get("a", || panic!("a should be kept")), uri("0123456789"));
+        cache.get("c", || uri("0123456789"));
+        assert!(cache.size <= 30);
+        cache.get("a", || panic!("a should be kept"));
+        assert_eq!(cache.get("b", || None), None);
+    }
+
+    #[test]
+    fn collects_unused_media() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = JsonStorage::new(dir.path().join("user-data.json"));
+        let media_dir = storage::media_dir(storage.path());
+        let used = store(&media_dir, b"used", "png").unwrap();
+        let spoken = store(&media_dir, b"spoken", "mp3").unwrap();
+        let unused = store(&media_dir, b"unused", "jpg").unwrap();
+        fs::write(media_dir.join("notes.txt"), "not ours").unwrap();
+        let data = data_using(&[&used], &[&spoken]);
+
+        assert_eq!(collect_garbage(&storage, &data).unwrap(), 1);
+        assert!(media_dir.join(&used).exists());
+        assert!(media_dir.join(&spoken).exists());
+        assert!(!media_dir.join(&unused).exists());
+        assert!(media_dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn keeps_media_used_by_backups_and_damaged_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        let mut storage = JsonStorage::new(path.clone());
+        let media_dir = storage::media_dir(&path);
+        let old = store(&media_dir, b"in a backup", "png").unwrap();
+        let damaged = store(&media_dir, b"in a damaged file", "png").unwrap();
+        let unused = store(&media_dir, b"unused", "png").unwrap();
+
+        // Saving twice backs up the first save
+        storage.save(&data_using(&[&old], &[])).unwrap();
+        storage.save(&UserData::empty()).unwrap();
+        fs::write(&path, serde_json::to_vec(&data_using(&[&damaged], &[])).unwrap()).unwrap();
+        persist::quarantine(&path).unwrap();
+
+        assert_eq!(collect_garbage(&storage, &UserData::empty()).unwrap(), 1);
+        assert!(media_dir.join(&old).exists());
+        assert!(media_dir.join(&damaged).exists());
+        assert!(!media_dir.join(&unused).exists());
+
+        // Nothing is deleted if a damaged copy can't be read at all
+        let unreadable = store(&media_dir, b"unreadable", "png").unwrap();
+        fs::write(path.with_file_name("user-data.corrupt-1.json"), "nonsense").unwrap();
+        assert_eq!(collect_garbage(&storage, &UserData::empty()).unwrap(), 0);
+        assert!(media_dir.join(&unreadable).exists());
+    }
+}
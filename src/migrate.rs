@@ -0,0 +1,110 @@
+//! Upgrades user data saved by older versions of the app, one version at a time
+use std::io;
+
+use log::info;
+use serde_json::{Map, Value};
+
+/// Version of the save format written by this version of the app
+pub const CURRENT_VERSION: u64 = 1;
+
+/// A step upgrading the save format from one version to the next
+type Migration = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// `MIGRATIONS[n]` upgrades version `n` to version `n + 1`
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [v0_to_v1];
+
+/// Gets the version a save was written with. Saves from before versioning are version 0.
+pub fn version_of(data: &Value) -> u64 {
+    data.get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+}
+
+/// Upgrades saved user data to [`CURRENT_VERSION`]
+pub fn migrate(mut data: Value) -> io::Result<Value> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let version = version_of(&data);
+    if version > CURRENT_VERSION {
+        return Err(invalid(format!(
+            "data was saved by a newer version of the app (format version {})",
+            version
+        )));
+    }
+    let object = data
+        .as_object_mut()
+        .ok_or_else(|| invalid("user data is not an object".into()))?;
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating user data from version {} to {}", from, from + 1);
+        migration(object).map_err(|e| invalid(format!("could not migrate from version {}: {}", from, e)))?;
+        object.insert("schema_version".into(), (from as u64 + 1).into());
+    }
+    Ok(data)
+}
+
+/// Version 1 gives every set an id
+fn v0_to_v1(data: &mut Map<String, Value>) -> Result<(), String> {
+    let sets = data
+        .get_mut("sets")
+        .and_then(Value::as_array_mut)
+        .ok_or("missing set list")?;
+    let id_of = |set: &Value| set.get("id").and_then(Value::as_u64).unwrap_or(0);
+    let mut next_id = sets.iter().map(id_of).max().unwrap_or(0) + 1;
+    for set in sets.iter_mut() {
+        if id_of(set) == 0 {
+            let set = set.as_object_mut().ok_or("set is not an object")?;
+            set.insert("id".into(), next_id.into());
+            next_id += 1;
+        }
+    }
+    data.insert("next_set_id".into(), next_id.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{FlashcardSet, UserData},
+        schedule::StudyDirection,
+    };
+
+    /// Loads a fixture saved by an older version
+    fn load_fixture(json: &str) -> UserData {
+        UserData::from_json(serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let data = load_fixture(include_str!("../fixtures/user-data-v0.json"));
+        assert_eq!(data.sets.len(), 2);
+        assert_ne!(data.sets[0].id(), data.sets[1].id());
+        let french = data.set_by_name("French").unwrap();
+        assert_eq!(french.flashcards[1].back.text, "Je suis");
+        assert!(french.flashcards[0].review.is_new());
+        assert!(french.flashcards[0].history.is_empty());
+        assert_eq!(french.direction, StudyDirection::Forward);
+    }
+
+    #[test]
+    fn loads_v1() {
+        let data = load_fixture(include_str!("../fixtures/user-data-v1.json"));
+        let german = data.set(data.sets[1].id()).unwrap();
+        assert_eq!(german.name, "German");
+        assert_eq!(german.direction, StudyDirection::Both);
+        assert_eq!(german.flashcards[0].review.reviews, 2);
+        assert_eq!(german.flashcards[0].history.len(), 2);
+    }
+
+    #[test]
+    fn new_sets_get_unused_ids() {
+        let mut data = load_fixture(include_str!("../fixtures/user-data-v0.json"));
+        let id = data.add_set(FlashcardSet::new("Spanish".into()));
+        assert_eq!(data.sets.iter().filter(|s| s.id() == id).count(), 1);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let data = serde_json::json!({ "schema_version": CURRENT_VERSION + 1, "sets": [] });
+        assert!(migrate(data).is_err());
+    }
+}
@@ -1,9 +1,23 @@
-use crate::{data::FlashcardSet, data::RichText, CurrentPage, CURRENT_PAGE, USER_DATA};
+use crate::{
+    data::{Color, FlashcardSet, RichText, SetId},
+    escape,
+    media::{self, MediaKind},
+    rich_text::{self, Document, Style},
+    schedule::StudyDirection,
+    storage, CurrentPage, CURRENT_PAGE, USER_DATA,
+};
 use dioxus::{
+    desktop::use_window,
     fermi::{use_read, use_set, Atom},
     prelude::*,
 };
-use std::cell::RefCell;
+use std::{
+    cell::RefCell,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 enum CardSide {
@@ -11,51 +25,430 @@ enum CardSide {
     Back,
 }
 
-static ACTIVE_SET: Atom<RefCell<Option<FlashcardSet>>> = |_| RefCell::new(None);
+/// What a toolbar button or shortcut does to the selected text, or all of it
+enum Format {
+    Style(fn(&mut Style) -> &mut bool),
+    List,
+    Color(Color),
+}
+
+/// A set open in the editor
+pub(crate) struct ActiveSet {
+    set: FlashcardSet,
+    /// Id of the saved set being edited, or `None` for a new set
+    original: Option<SetId>,
+    /// The card and side last typed in - pictures and sounds dropped on the window are added to it
+    focused: Option<(u64, CardSide)>,
+}
+
+pub(crate) static ACTIVE_SET: Atom<RefCell<Option<ActiveSet>>> = |_| RefCell::new(None);
+/// Counts the files dropped on the window, so the side they were added to is shown again
+static FILES_DROPPED: Atom<u32> = |_| 0;
+
+/// Colours offered in the toolbar - picked with Ctrl and 1 to 8
+const PALETTE: [&str; 8] = ["#000000", "#c0392b", "#d35400", "#b7950b", "#27ae60", "#2980b9", "#8e44ad", "#7f8c8d"];
+
+/// Adds a file from the media store to a side, unless it's there already
+fn attach(text: &mut RichText, kind: MediaKind, name: String) {
+    let names = match kind {
+        MediaKind::Image => &mut text.images,
+        MediaKind::Audio => &mut text.audio,
+    };
+    if !names.contains(&name) {
+        names.push(name);
+    }
+}
+
+/// Adds image and sound files to a side, returning why any of them couldn't be
+fn add_files(text: &mut RichText, media_dir: &Path, paths: &[PathBuf]) -> Option<String> {
+    let mut failures = vec![];
+    for path in paths {
+        match media::add_file(media_dir, path) {
+            Ok((kind, name)) => attach(text, kind, name),
+            Err(e) => failures.push(format!("{} couldn't be added: {}", path.display(), e)),
+        }
+    }
+    if failures.is_empty() {
+        None
+    } else {
+        Some(failures.join("\n"))
+    }
+}
+
+/// Id of the text box for one side of a card
+fn text_box_id(id: u64, side: CardSide) -> String {
+    match side {
+        CardSide::Front => format!("card-{}-front", id),
+        CardSide::Back => format!("card-{}-back", id),
+    }
+}
+
+/// Script sending the selection in a text box back to the app, through the hidden input with the same id and `-selection`.
+/// The selection can only be read in the page, so this is how the toolbar finds out what to format.
+fn selection_script(id: &str) -> String {
+    format!(
+        "(function () {{
+            const text = document.getElementById('{0}');
+            const selection = document.getElementById('{0}-selection');
+            if (text && selection) {{
+                selection.value = text.selectionStart + ' ' + text.selectionEnd;
+                selection.dispatchEvent(new Event('input', {{ bubbles: true }}));
+            }}
+        }})();",
+        id
+    )
+}
+
+/// The byte offset in `text` of an offset counted in UTF-16 units, as the page counts them
+fn byte_offset(text: &str, utf16: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if units >= utf16 {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// Adds the picture on the clipboard to the media store, or `None` if there isn't one
+fn paste_image(media_dir: &Path) -> Option<io::Result<String>> {
+    let image = arboard::Clipboard::new().and_then(|mut c| c.get_image()).ok()?;
+    Some(media::add_pixels(media_dir, image.width as u32, image.height as u32, &image.bytes))
+}
 
 #[derive(Props, PartialEq)]
 struct FlashcardInputProps {
     id: u64,
     side: CardSide,
 }
+/// Text box for one side of a card, with a toolbar to format the selected text or all of it, and its pictures and sounds
 fn FlashcardInput(cx: Scope<FlashcardInputProps>) -> Element {
     let active_set = use_read(&cx, ACTIVE_SET);
+    let user_data = use_read(&cx, USER_DATA);
+    // Shown again when files are dropped on the window, in case they were added here
+    use_read(&cx, FILES_DROPPED);
     let set_borrow = active_set.borrow();
-    let card = &set_borrow.as_ref().unwrap()[cx.props.id];
+    let card = &set_borrow.as_ref().unwrap().set[cx.props.id];
     let text = match cx.props.side {
         CardSide::Front => &card.front,
         CardSide::Back => &card.back,
     };
-    rsx!(cx, textarea {
-        rows: "4", cols: "50",
-        style: "color: {text.color};",
-        onchange: move |env| {
-            let mut set_borrow = active_set.borrow_mut();
-            let card = &mut set_borrow.as_mut().unwrap()[cx.props.id];
-            let text = match cx.props.side {
-                CardSide::Front => &mut card.front,
-                CardSide::Back => &mut card.back
-            };
-            text.text = env.data.value.clone();
-        },
-        "{text.text}"
+    // The markup as it was typed - it's only rewritten when the toolbar changes the text
+    let (draft, set_draft) = use_state(&cx, || text.markup());
+    let (media_error, set_media_error) = use_state(&cx, || None::<String>);
+    let media_dir = move || storage::media_dir(user_data.borrow().storage().path());
+
+    // Changes the text of this side, returning its new markup
+    let update = move |change: &mut dyn FnMut(&mut RichText)| {
+        let mut set_borrow = active_set.borrow_mut();
+        let card = &mut set_borrow.as_mut().unwrap().set[cx.props.id];
+        let text = match cx.props.side {
+            CardSide::Front => &mut card.front,
+            CardSide::Back => &mut card.back
+        };
+        change(text);
+        text.markup()
+    };
+    let window = use_window(&cx);
+    // The format waiting for the selection to come back from the page
+    let pending = use_ref(&cx, || None::<Format>);
+    let format_selected = move |format: Format| {
+        *pending.write() = Some(format);
+        window.eval(selection_script(&text_box_id(cx.props.id, cx.props.side)));
+    };
+    // Formats the selected part of the markup, or all of the text if nothing is selected
+    let apply = move |format: Format, selection: Range<usize>| {
+        if let Format::Color(color) = &format {
+            user_data.borrow_mut().modify_meta(|data| data.remember_color(color));
+        }
+        if selection.is_empty() {
+            set_draft(update(&mut |text| match &format {
+                Format::Style(style) => text.change_document(|doc| doc.toggle(*style)),
+                Format::List => text.change_document(Document::toggle_list),
+                Format::Color(color) => text.color = color.clone(),
+            }));
+            return;
+        }
+        let markup = match &format {
+            Format::Style(style) => {
+                let mut on = Style::default();
+                *style(&mut on) = true;
+                rich_text::format_selection(draft, selection, &on)
+            }
+            Format::List => rich_text::toggle_list_lines(draft, selection),
+            Format::Color(color) => {
+                let colored = Style {
+                    color: Some(color.clone()),
+                    ..Style::default()
+                };
+                rich_text::format_selection(draft, selection, &colored)
+            }
+        };
+        update(&mut |text| text.set_document(Document::parse(&markup)));
+        set_draft(markup);
+    };
+    let toggle = move |style: fn(&mut Style) -> &mut bool| format_selected(Format::Style(style));
+    let toggle_list = move || format_selected(Format::List);
+    let choose_color = move |color: &str| {
+        // Only real colours go in the style attribute
+        if let Some(color) = Color::parse(color) {
+            format_selected(Format::Color(color));
+        }
+    };
+    // The button, its description, what it turns on and off, and the key that does it with Ctrl
+    let formats: [(&str, &str, fn(&mut Style) -> &mut bool, &str); 5] = [
+        ("B", "Bold", |s| &mut s.bold, "b"),
+        ("I", "Italic", |s| &mut s.italic, "i"),
+        ("U", "Underline", |s| &mut s.underline, "u"),
+        ("S", "Strikethrough", |s| &mut s.strike, "d"),
+        ("<>", "Code", |s| &mut s.code, "e"),
+    ];
+
+    let recent = user_data.borrow().get().recent_colors.clone();
+    // The colour picker only shows `#rrggbb` colours
+    let picker_color = if text.color.as_str().starts_with('#') && text.color.as_str().len() == 7 {
+        text.color.as_str()
+    } else {
+        "#000000"
+    };
+    let text_style = escape::color_style(Some(&text.color));
+    let text_box = text_box_id(cx.props.id, cx.props.side);
+    let images = text.images.iter().map(|name| {
+        let removed = name.clone();
+        let uri = media::cached_uri(&media_dir(), name);
+        rsx!(cx, div {
+            key: "{name}",
+            class: "image-thumbnail",
+            uri.map(|uri| rsx!(cx, img { src: "{uri}" }))
+            button {
+                "type": "button",
+                title: "Remove picture",
+                onclick: move |_| {
+                    update(&mut |text| text.images.retain(|n| *n != removed));
+                    cx.needs_update();
+                },
+                "×"
+            }
+        })
+    });
+    let sounds = text.audio.iter().map(|name| {
+        let removed = name.clone();
+        let uri = media::cached_uri(&media_dir(), name);
+        rsx!(cx, div {
+            key: "{name}",
+            class: "sound-input",
+            uri.map(|uri| rsx!(cx, audio { controls: "true", src: "{uri}" }))
+            button {
+                "type": "button",
+                title: "Remove sound",
+                onclick: move |_| {
+                    update(&mut |text| text.audio.retain(|n| *n != removed));
+                    cx.needs_update();
+                },
+                "×"
+            }
+        })
+    });
+    // Adds files chosen with a file picker
+    let pick_files = move |name: &str, extensions: &[&str]| {
+        if let Some(paths) = rfd::FileDialog::new().add_filter(name, extensions).pick_files() {
+            let mut error = None;
+            update(&mut |text| error = add_files(text, &media_dir(), &paths));
+            set_media_error(error);
+            cx.needs_update();
+        }
+    };
+    // Formatted text is shown as it'll look under the markup
+    let preview = text.doc.is_some().then(|| rsx!(cx, rich_text::RichTextView { text: text.clone() }));
+    rsx!(cx, div {
+        div {
+            class: "format-toolbar",
+            formats.iter().map(|(label, name, style, key)| {
+                let style = *style;
+                let shortcut = key.to_uppercase();
+                rsx!(cx, button {
+                    key: "{name}",
+                    "type": "button",
+                    title: "{name} (Ctrl+{shortcut})",
+                    onclick: move |_| toggle(style),
+                    "{label}"
+                })
+            })
+            button {
+                "type": "button",
+                title: "List (Ctrl+L)",
+                onclick: move |_| toggle_list(),
+                "•"
+            }
+            PALETTE.iter().enumerate().map(|(i, color)| rsx!(cx, button {
+                key: "{color}",
+                "type": "button",
+                class: "swatch",
+                title: "{color} (Ctrl+{i + 1})",
+                style: "background-color: {color};",
+                onclick: move |_| choose_color(color),
+            }))
+            recent.iter().map(|color| {
+                let picked = color.to_string();
+                rsx!(cx, button {
+                    key: "recent-{color}",
+                    "type": "button",
+                    class: "swatch recent",
+                    title: "{color}",
+                    style: "background-color: {color};",
+                    onclick: move |_| choose_color(&picked),
+                })
+            })
+            input {
+                "type": "color",
+                title: "Other colour",
+                value: "{picker_color}",
+                onchange: move |e| choose_color(&e.value),
+            }
+        }
+        textarea {
+            id: "{text_box}",
+            rows: "4", cols: "50",
+            style: "{text_style}",
+            oninput: move |e| {
+                update(&mut |text| text.set_document(Document::parse(&e.value)));
+                set_draft(e.value.clone());
+            },
+            onfocus: move |_| {
+                if let Some(active) = active_set.borrow_mut().as_mut() {
+                    active.focused = Some((cx.props.id, cx.props.side));
+                }
+            },
+            onkeydown: move |e| {
+                if !(e.ctrl_key || e.meta_key) {
+                    return;
+                }
+                let key = e.key.to_lowercase();
+                if let Some((_, _, style, _)) = formats.iter().find(|f| f.3 == key) {
+                    toggle(*style);
+                } else if key == "l" {
+                    toggle_list();
+                } else if let Some(color) = key.parse::<usize>().ok().and_then(|n| PALETTE.get(n.wrapping_sub(1))) {
+                    choose_color(color);
+                } else if key == "v" {
+                    // The text box pastes text itself, but pictures have to be fetched
+                    match paste_image(&media_dir()) {
+                        Some(Ok(name)) => {
+                            update(&mut |text| attach(text, MediaKind::Image, name.clone()));
+                            cx.needs_update();
+                        }
+                        Some(Err(e)) => set_media_error(Some(format!("The picture couldn't be pasted: {}", e))),
+                        None => {}
+                    }
+                }
+            },
+            "{draft}"
+        }
+        input {
+            "type": "hidden",
+            id: "{text_box}-selection",
+            oninput: move |e| {
+                let format = match pending.write().take() {
+                    Some(format) => format,
+                    None => return,
+                };
+                let mut offsets = e.value.split(' ').filter_map(|n| n.parse().ok());
+                if let (Some(start), Some(end)) = (offsets.next(), offsets.next()) {
+                    apply(format, byte_offset(draft, start)..byte_offset(draft, end));
+                }
+            },
+        }
+        preview
+        div {
+            class: "card-images",
+            images
+            button {
+                "type": "button",
+                title: "Or drop pictures on the window, or paste them",
+                onclick: move |_| pick_files("Pictures", &media::IMAGE_EXTENSIONS),
+                "Add picture"
+            }
+        }
+        div {
+            class: "card-sounds",
+            sounds
+            button {
+                "type": "button",
+                title: "Or drop sounds on the window",
+                onclick: move |_| pick_files("Sounds", &media::AUDIO_EXTENSIONS),
+                "Add sound"
+            }
+        }
+        media_error.as_ref().map(|e| rsx!(cx, p { class: "warning", "{e}" }))
     })
 }
 
-/// The flashcard note input page
-pub fn InputFlashcards(cx: Scope) -> Element {
+#[derive(Props, PartialEq)]
+pub struct InputFlashcardsProps {
+    /// Id of the set to edit, or `None` to create a new one
+    edit: Option<SetId>,
+}
+/// The flashcard note input page - creates a new set, or edits an existing one
+pub fn InputFlashcards(cx: Scope<InputFlashcardsProps>) -> Element {
     let set = use_read(&cx, ACTIVE_SET);
-    if set.borrow().is_none() {
-        // just created the component
-        *set.borrow_mut() = Some(FlashcardSet::new("".into()));
+    let user_data = use_read(&cx, USER_DATA);
+    let drop_count = use_read(&cx, FILES_DROPPED);
+    let set_drop_count = use_set(&cx, FILES_DROPPED);
+    let (drop_error, set_drop_error) = use_state(&cx, || None::<String>);
+    // Files are dropped outside of dioxus, so check for them
+    let update = cx.schedule_update();
+    use_future(&cx, || async move {
+        loop {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            if media::files_dropped() {
+                update();
+            }
+        }
+    });
+    let stale = set.borrow().as_ref().map(|a| &a.original) != Some(&cx.props.edit);
+    if stale {
+        // just created the component, or switched to another set
+        let mut active = ActiveSet {
+            set: FlashcardSet::new("".into()),
+            original: None,
+            focused: None,
+        };
+        if let Some(id) = cx.props.edit {
+            if let Some(existing) = user_data.borrow().get().set(id) {
+                active.set = existing.clone();
+                active.original = Some(id);
+            }
+        }
+        if active.set.flashcards.is_empty() {
+            active.set.add(RichText::empty(), RichText::empty());
+        }
+        *set.borrow_mut() = Some(active);
+        // Anything dropped before the editor was open wasn't meant for it
+        media::take_dropped();
+    }
+    let dropped = media::take_dropped();
+    if !dropped.is_empty() {
+        let mut set_borrow = set.borrow_mut();
+        let active = set_borrow.as_mut().unwrap();
+        let focused = active.focused;
+        let card = focused.and_then(|(id, side)| {
+            let card = active.set.flashcards.iter_mut().find(|f| f.id() == id)?;
+            Some(match side {
+                CardSide::Front => &mut card.front,
+                CardSide::Back => &mut card.back,
+            })
+        });
+        set_drop_error(match card {
+            Some(text) => add_files(text, &storage::media_dir(user_data.borrow().storage().path()), &dropped),
+            None => Some("Click in a side of a card to drop pictures and sounds on it".into()),
+        });
+        set_drop_count(drop_count.wrapping_add(1));
     }
-    set.borrow_mut()
-        .as_mut()
-        .unwrap()
-        .add(RichText::empty(), RichText::empty());
     let set_borrow = set.borrow();
-    let set_ = set_borrow.as_ref().unwrap();
-    let flashcard_list = set_.flashcards.iter().map(|f| {
+    let set_ = &set_borrow.as_ref().unwrap().set;
+    let last = set_.flashcards.len().saturating_sub(1);
+    let flashcard_list = set_.flashcards.iter().enumerate().map(|(i, f)| {
         let key = f.id();
         rsx!(cx,
             div {
@@ -69,6 +462,35 @@ pub fn InputFlashcards(cx: Scope) -> Element {
                     id: f.id(),
                     side: CardSide::Back
                 },
+                div {
+                    class: "card-controls",
+                    button {
+                        "type": "button",
+                        disabled: "{i == 0}",
+                        onclick: move |_| {
+                            set.borrow_mut().as_mut().unwrap().set.flashcards.swap(i, i.saturating_sub(1));
+                            cx.needs_update();
+                        },
+                        "↑"
+                    }
+                    button {
+                        "type": "button",
+                        disabled: "{i == last}",
+                        onclick: move |_| {
+                            set.borrow_mut().as_mut().unwrap().set.flashcards.swap(i, (i + 1).min(last));
+                            cx.needs_update();
+                        },
+                        "↓"
+                    }
+                    button {
+                        "type": "button",
+                        onclick: move |_| {
+                            set.borrow_mut().as_mut().unwrap().set.remove(key);
+                            cx.needs_update();
+                        },
+                        "Remove"
+                    }
+                }
             }
         )
     });
@@ -81,28 +503,61 @@ pub fn InputFlashcards(cx: Scope) -> Element {
                 class: "center-div",
                 input {
                     "type": "input",
+                    value: "{set_.name}",
                     onchange: move |e| {
-                        set.borrow_mut().as_mut().unwrap().name = e.value.clone();
+                        set.borrow_mut().as_mut().unwrap().set.name = e.value.clone();
+                    }
+                }
+                label {
+                    "Study "
+                    select {
+                        oninput: move |e| {
+                            if let Some(direction) = StudyDirection::from_name(&e.value) {
+                                set.borrow_mut().as_mut().unwrap().set.direction = direction;
+                            }
+                        },
+                        StudyDirection::ALL.iter().map(|d| rsx!(cx, option {
+                            key: "{d.name()}",
+                            value: "{d.name()}",
+                            selected: "{*d == set_.direction}",
+                            "{d.label()}"
+                        }))
                     }
                 }
+                drop_error.as_ref().map(|e| rsx!(cx, p { class: "warning", "{e}" }))
+                p {
+                    class: "markup-help",
+                    "Formatting: **bold**, *italic*, __underline__, ~~strike~~, `code`, ^{{superscript}}, _{{subscript}}, {{red|colour}}, and lines starting with - or 1. for lists"
+                }
                 flashcard_list
+                button {
+                    "type": "button",
+                    onclick: move |_| {
+                        set.borrow_mut().as_mut().unwrap().set.add(RichText::empty(), RichText::empty());
+                        cx.needs_update();
+                    },
+                    "Add card"
+                }
                 // Submit button
                 button {
                     "type": "button",
                     onclick: move |_| {
-                        let user_data = use_read(&cx, USER_DATA);
                         let set_borrow = set.borrow();
-                        let name = &set_borrow.as_ref().unwrap().name;
-                        // Check if a set with this name already exists
-                        if user_data.borrow().get().sets.iter().any(|s| &s.name == name) {
+                        let active = set_borrow.as_ref().unwrap();
+                        let name = &active.set.name;
+                        // Check if another set with this name already exists
+                        let taken = user_data.borrow().get().name_taken(name, active.original);
+                        if taken {
                             // little warning
                             set_warning(true);
                         } else {
                             drop(set_borrow);
                             // It doesn't - success
-                            user_data.borrow_mut().modify(|d| d.sets.push(
-                                set.borrow_mut().take().unwrap()
-                            ));
+                            let mut edited = set.borrow_mut().take().unwrap().set;
+                            // Blank cards are left over from adding
+                            edited.flashcards.retain(|f| !f.front.is_empty() || !f.back.is_empty());
+                            // Replaces the set being edited (keeping its place in the list), or adds a new one
+                            user_data.borrow_mut().save_edited_set(edited);
                             (use_set(&cx, CURRENT_PAGE))(CurrentPage::Flashcards);
                         }
                     },
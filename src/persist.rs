@@ -0,0 +1,204 @@
+//! Crash-safe file writes and rotating backups of the save file
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+use crate::schedule;
+
+/// How many backups are kept
+const MAX_BACKUPS: usize = 10;
+/// Minimum time between backups (seconds) - saves happen far more often than this
+const BACKUP_INTERVAL: u64 = 60 * 30;
+
+/// Writes `contents` to `path` so that a crash leaves either the old or the new file, never half of one.
+/// The data is written to a temporary file, flushed to disk, then renamed over the old file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    // Make sure the rename itself reaches the disk
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Moves a damaged file aside so it can't be saved over, returning where it went
+pub fn quarantine(path: &Path) -> io::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let quarantined = path.with_file_name(format!("{}.corrupt-{}.{}", stem, schedule::now(), extension));
+    warn!("Moving damaged {} to {}", path.display(), quarantined.display());
+    fs::rename(path, &quarantined)?;
+    Ok(quarantined)
+}
+
+/// Damaged copies of `path` moved aside by [`quarantine`], newest first
+pub fn quarantined(path: &Path) -> Vec<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{}.corrupt-", stem);
+    let suffix = format!(".{}", extension);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut found: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let time: u64 = name.strip_prefix(&prefix)?.strip_suffix(&suffix)?.parse().ok()?;
+            Some((time, entry.path()))
+        })
+        .collect();
+    found.sort_by_key(|(time, _)| std::cmp::Reverse(*time));
+    found.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Folder the backups of `path` are kept in
+pub fn backup_dir(path: &Path) -> PathBuf {
+    path.with_file_name("backups")
+}
+
+/// All the backups of `path`, newest first, with when they were taken
+pub fn backups(path: &Path) -> Vec<(u64, PathBuf)> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let prefix = format!("{}-", stem);
+    let mut backups: Vec<_> = fs::read_dir(backup_dir(path))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let time: u64 = name.strip_prefix(&prefix)?.split('.').next()?.parse().ok()?;
+            Some((time, entry.path()))
+        })
+        .collect();
+    backups.sort_by_key(|(time, _)| std::cmp::Reverse(*time));
+    backups
+}
+
+/// Copies the current `path` into the backup folder, if the last backup is old enough,
+/// and deletes the oldest backups beyond [`MAX_BACKUPS`]
+pub fn backup(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let now = schedule::now();
+    let existing = backups(path);
+    if existing.first().map_or(false, |(time, _)| now < time + BACKUP_INTERVAL) {
+        return Ok(());
+    }
+    let dir = backup_dir(path);
+    fs::create_dir_all(&dir)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let backup_path = dir.join(format!("{}-{}.{}", stem, now, extension));
+    info!("Backing up {} to {}", path.display(), backup_path.display());
+    write_atomic(&backup_path, &fs::read(path)?)?;
+    for (_, old) in existing.iter().skip(MAX_BACKUPS - 1) {
+        if let Err(e) = fs::remove_file(old) {
+            warn!("Could not remove old backup {}: {}", old.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Loads the newest backup of `path` that `load` accepts
+pub fn load_newest_backup<T>(path: &Path, load: impl Fn(&Path) -> io::Result<T>) -> Option<(PathBuf, T)> {
+    backups(path).into_iter().find_map(|(_, backup)| match load(&backup) {
+        Ok(data) => Some((backup, data)),
+        Err(e) => {
+            warn!("Backup {} is unusable: {}", backup.display(), e);
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Puts a backup of `path` taken at `time` in the backup folder
+    fn old_backup(path: &Path, time: u64, contents: &str) -> PathBuf {
+        let dir = backup_dir(path);
+        fs::create_dir_all(&dir).unwrap();
+        let backup = dir.join(format!("user-data-{}.json", time));
+        fs::write(&backup, contents).unwrap();
+        backup
+    }
+
+    #[test]
+    fn writes_replace_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        let names: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["user-data.json"]);
+    }
+
+    #[test]
+    fn keeps_the_newest_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        // Nothing to back up yet
+        backup(&path).unwrap();
+        assert!(backups(&path).is_empty());
+
+        fs::write(&path, "current").unwrap();
+        let now = schedule::now();
+        let oldest = now - BACKUP_INTERVAL * (MAX_BACKUPS as u64 + 2);
+        for i in 0..MAX_BACKUPS as u64 {
+            old_backup(&path, oldest + i * BACKUP_INTERVAL, "old");
+        }
+        backup(&path).unwrap();
+        let kept = backups(&path);
+        assert_eq!(kept.len(), MAX_BACKUPS);
+        assert!(kept[0].0 >= now);
+        assert_eq!(fs::read_to_string(&kept[0].1).unwrap(), "current");
+        assert_eq!(kept.last().unwrap().0, oldest + BACKUP_INTERVAL);
+
+        // Too soon after the last one for another
+        fs::write(&path, "newer").unwrap();
+        backup(&path).unwrap();
+        assert_eq!(backups(&path), kept);
+    }
+
+    #[test]
+    fn loads_the_newest_backup_that_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        assert!(load_newest_backup(&path, |p| fs::read_to_string(p)).is_none());
+        old_backup(&path, 100, "good");
+        let usable = old_backup(&path, 200, "also good");
+        old_backup(&path, 300, "corrupt");
+        let load = |p: &Path| match fs::read_to_string(p)? {
+            text if text.contains("good") => Ok(text),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt")),
+        };
+        assert_eq!(load_newest_backup(&path, load), Some((usable, "also good".to_owned())));
+    }
+
+    #[test]
+    fn finds_quarantined_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        fs::write(&path, "damaged").unwrap();
+        let moved = quarantine(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "damaged");
+        assert_eq!(quarantined(&path), [moved]);
+    }
+}
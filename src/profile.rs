@@ -0,0 +1,248 @@
+//! Where the data is kept, and named profiles - separate sets of data for people sharing a computer
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use dioxus::{
+    fermi::{use_read, use_set, Atom},
+    prelude::*,
+};
+use log::{info, warn};
+use platform_dirs::AppDirs;
+
+use crate::{
+    data::UserDataAccessor, flashcards::DELETED_SET, media, note_input::ACTIVE_SET, persist, storage, CurrentPage,
+    CURRENT_PAGE, USER_DATA,
+};
+
+/// Environment variable overriding the folder all data is kept in (also set by `--data-dir`)
+pub const DATA_DIR_VAR: &str = "MAGISTRAX_DATA_DIR";
+/// Environment variable choosing the profile to open (also set by `--profile`)
+pub const PROFILE_VAR: &str = "MAGISTRAX_PROFILE";
+/// The profile used until others are made - its data is kept straight in the data folder
+pub const DEFAULT_PROFILE: &str = "default";
+/// Longest allowed profile name
+const MAX_NAME_LENGTH: usize = 40;
+/// Names Windows won't make a folder with, whatever their case
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// An atom containing the name of the open profile
+pub static PROFILE: Atom<String> = |_| current();
+
+/// Folder all the data is kept in - `MAGISTRAX_DATA_DIR` if it's set, or the platform's data folder
+pub fn data_dir() -> io::Result<PathBuf> {
+    if let Some(dir) = env::var_os(DATA_DIR_VAR) {
+        return Ok(dir.into());
+    }
+    // Get some platform-specific save dirs
+    AppDirs::new(Some("magistrax"), true)
+        .map(|dirs| dirs.data_dir)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no folder to keep data in could be found - set {} to choose one", DATA_DIR_VAR),
+            )
+        })
+}
+
+/// Whether `name` can be used for a profile - it's used as a folder name
+pub fn valid_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.trim() == name
+        && name.chars().all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        && !RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+/// The profile among `names` that `name` would clash with - some filesystems don't tell "Work" and "work" apart
+fn clashing<'a>(names: &'a [String], name: &str) -> Option<&'a String> {
+    let name = name.to_lowercase();
+    names.iter().find(|existing| existing.to_lowercase() == name)
+}
+
+/// Folder a profile's data is kept in
+pub fn profile_dir(name: &str) -> io::Result<PathBuf> {
+    let dir = data_dir()?;
+    // The default profile is where the data was kept before there were profiles
+    Ok(if name == DEFAULT_PROFILE {
+        dir
+    } else {
+        dir.join("profiles").join(name)
+    })
+}
+
+/// Names of all the profiles, the default first
+pub fn profiles() -> Vec<String> {
+    let mut names: Vec<String> = data_dir()
+        .map(|dir| dir.join("profiles"))
+        .and_then(fs::read_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| valid_name(name) && name != DEFAULT_PROFILE)
+        .collect();
+    names.sort();
+    names.insert(0, DEFAULT_PROFILE.into());
+    names
+}
+
+/// File remembering the last opened profile
+fn current_file(dir: &Path) -> PathBuf {
+    dir.join("current-profile")
+}
+
+/// The profile to open - from `MAGISTRAX_PROFILE`, or the one opened last time
+pub fn current() -> String {
+    let chosen = env::var(PROFILE_VAR).ok().or_else(|| {
+        let dir = data_dir().ok()?;
+        fs::read_to_string(current_file(&dir)).ok()
+    });
+    match chosen {
+        Some(name) if valid_name(name.trim()) => name.trim().into(),
+        Some(name) => {
+            warn!("Invalid profile name {:?}, using the default profile", name);
+            DEFAULT_PROFILE.into()
+        }
+        None => DEFAULT_PROFILE.into(),
+    }
+}
+
+/// Remembers the profile to open next time
+fn remember(name: &str) -> io::Result<()> {
+    let dir = data_dir()?;
+    fs::create_dir_all(&dir)?;
+    persist::write_atomic(&current_file(&dir), name.as_bytes())
+}
+
+/// Opens the data of a profile, clearing out media its cards don't use any more
+pub fn open(name: &str) -> UserDataAccessor {
+    info!("Opening profile {}", name);
+    let storage = match profile_dir(name) {
+        Ok(dir) => storage::open(&dir),
+        Err(e) => Box::new(storage::Unavailable::new(e)),
+    };
+    // Without a data file there's nothing to say which media are in use
+    let existed = storage.path().exists();
+    let accessor = UserDataAccessor::open(storage);
+    if existed && accessor.recovery().is_none() {
+        if let Err(e) = media::collect_garbage(accessor.storage().as_ref(), accessor.get()) {
+            warn!("Could not clear out unused media: {}", e);
+        }
+    }
+    accessor
+}
+
+/// Lets the user pick which profile to use, or make a new one
+pub fn ProfileSwitcher(cx: Scope) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    let profile = use_read(&cx, PROFILE);
+    let set_profile = use_set(&cx, PROFILE);
+    let set_page = use_set(&cx, CURRENT_PAGE);
+    let set_deleted = use_set(&cx, DELETED_SET);
+    let active_set = use_read(&cx, ACTIVE_SET);
+    let (new_name, set_new_name) = use_state(&cx, String::new);
+    let (message, set_message) = use_state(&cx, || None::<String>);
+    let names = profiles();
+
+    let switch_to = move |name: String| {
+        if let Err(e) = remember(&name) {
+            warn!("Could not remember the profile: {}", e);
+        }
+        // Dropping the old profile's data saves anything that's waiting
+        *user_data.borrow_mut() = open(&name);
+        // Forget anything that belonged to the old profile
+        set_deleted(None);
+        *active_set.borrow_mut() = None;
+        set_profile(name);
+        set_page(CurrentPage::HomePage);
+    };
+
+    rsx!(cx, div {
+        class: "profile-switcher",
+        label {
+            "Profile: "
+            select {
+                oninput: move |e| {
+                    if e.value != *profile {
+                        switch_to(e.value.clone());
+                    }
+                },
+                names.iter().map(|name| rsx!(cx, option {
+                    key: "{name}",
+                    value: "{name}",
+                    selected: "{name == profile}",
+                    "{name}"
+                }))
+            }
+        }
+        input {
+            placeholder: "New profile",
+            value: "{new_name}",
+            oninput: move |e| set_new_name(e.value.clone()),
+        }
+        button {
+            "type": "button",
+            onclick: move |_| {
+                let name = new_name.trim().to_owned();
+                if !valid_name(&name) {
+                    set_message(Some("Profile names can only use letters, numbers, spaces, - and _".into()));
+                } else if let Some(existing) = clashing(&profiles(), &name) {
+                    set_message(Some(format!("There's already a profile called {}", existing)));
+                } else {
+                    set_message(None);
+                    set_new_name(String::new());
+                    switch_to(name);
+                }
+            },
+            "Create"
+        }
+        message.as_ref().map(|m| rsx!(cx, p { class: "warning", "{m}" }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_names() {
+        for name in ["Work", "ana-maria", "Ünïcode 2", "x_y"] {
+            assert!(valid_name(name), "{}", name);
+        }
+        for name in ["", "  ", " padded", "..", ".", "a/b", "a\\b", "a:b", "con", "Nul", "LPT1", &"x".repeat(41)] {
+            assert!(!valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn names_clash_whatever_their_case() {
+        let names = vec![DEFAULT_PROFILE.to_owned(), "Work".into()];
+        assert_eq!(clashing(&names, "work"), Some(&names[1]));
+        assert_eq!(clashing(&names, "DEFAULT"), Some(&names[0]));
+        assert_eq!(clashing(&names, "Home"), None);
+    }
+
+    // Everything that reads the data folder is in one test, as it's set for the whole process
+    #[test]
+    fn keeps_profiles_in_the_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        env::set_var(DATA_DIR_VAR, dir.path());
+        assert_eq!(data_dir().unwrap(), dir.path());
+        assert_eq!(profile_dir(DEFAULT_PROFILE).unwrap(), dir.path());
+        assert_eq!(profile_dir("Work").unwrap(), dir.path().join("profiles").join("Work"));
+        assert_eq!(profiles(), [DEFAULT_PROFILE]);
+
+        for name in ["Work", "Home", "not..valid", DEFAULT_PROFILE] {
+            fs::create_dir_all(dir.path().join("profiles").join(name)).unwrap();
+        }
+        fs::write(dir.path().join("profiles").join("a file"), "").unwrap();
+        assert_eq!(profiles(), [DEFAULT_PROFILE, "Home", "Work"]);
+        env::remove_var(DATA_DIR_VAR);
+    }
+}
@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use dioxus::{
+    fermi::{use_read, use_set},
+    prelude::*,
+};
+
+use crate::{
+    data::{Salvaged, UserData},
+    persist, schedule, CurrentPage, CURRENT_PAGE, USER_DATA,
+};
+
+/// Describes how long ago a unix time was
+fn format_age(time: u64) -> String {
+    let secs = schedule::now().saturating_sub(time);
+    match secs {
+        0..=119 => "just now".into(),
+        120..=7199 => format!("{} minutes ago", secs / 60),
+        7200..=172_799 => format!("{} hours ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+/// Shown when the user data couldn't be loaded - lets the user choose what to recover.
+/// Nothing is saved until they do.
+pub fn Recovery(cx: Scope) -> Element {
+    let user_data = use_read(&cx, USER_DATA);
+    let set_page = use_set(&cx, CURRENT_PAGE);
+    let (message, set_message) = use_state(&cx, || None::<String>);
+    // What could be read from the damaged file, once asked for
+    let salvaged = use_ref(&cx, || None::<Result<Salvaged, String>>);
+    let failure = match user_data.borrow().recovery() {
+        Some(failure) => failure.clone(),
+        None => return rsx!(cx, div {
+            class: "center-div",
+            h1 {"Recovery"}
+            p {"Your data loaded fine - there's nothing to recover."}
+        }),
+    };
+    let backups = persist::backups(user_data.borrow().storage().path());
+    let restore = move |path: &Path| {
+        let loaded = user_data.borrow().storage().read_backup(path);
+        match loaded {
+            Ok(data) => {
+                user_data.borrow_mut().recover(data);
+                set_page(CurrentPage::HomePage);
+            }
+            Err(e) => set_message(Some(format!("That backup couldn't be read either: {}", e))),
+        }
+    };
+    let damaged_file = failure.quarantined.clone();
+    let salvageable = failure
+        .quarantined
+        .as_ref()
+        .map_or(false, |p| p.extension().map_or(false, |e| e == "json"));
+    let quarantined = failure
+        .quarantined
+        .as_ref()
+        .map(|p| p.display().to_string());
+
+    let salvaged_preview = salvaged.read().as_ref().map(|result| match result {
+        Ok(rescued) => {
+            let lost = (rescued.lost_sets > 0 || rescued.lost_cards > 0).then(|| rsx!(cx, p {
+                class: "warning",
+                "{rescued.lost_sets} sets and {rescued.lost_cards} cards couldn't be read"
+            }));
+            rsx!(cx, div {
+                class: "center-div",
+                ul {
+                    rescued.data.sets.iter().map(|s| {
+                        let count = s.flashcards.len();
+                        rsx!(cx, li { key: "{s.id()}", "{s.name} ({count} cards)" })
+                    })
+                }
+                lost
+                button {
+                    "type": "button",
+                    onclick: move |_| {
+                        if let Some(Ok(salvaged)) = salvaged.write().take() {
+                            user_data.borrow_mut().recover(salvaged.data);
+                            set_page(CurrentPage::HomePage);
+                        }
+                    },
+                    "Use these sets"
+                }
+            })
+        }
+        Err(e) => rsx!(cx, p { class: "warning", "Nothing could be recovered: {e}" }),
+    });
+
+    rsx!(cx, div {
+        class: "center-div",
+        h1 {"Recovery"}
+        p {"Your saved data couldn't be loaded: {failure.error}"}
+        quarantined.as_ref().map(|path| rsx!(cx, p {
+            "The damaged file has been moved to {path} - it won't be touched."
+        }))
+        p {"Nothing will be saved until you choose what to do."}
+        message.as_ref().map(|m| rsx!(cx, p { class: "warning", "{m}" }))
+
+        h2 {"Restore a backup"}
+        (!backups.is_empty()).then(|| rsx!(cx, button {
+            "type": "button",
+            onclick: move |_| {
+                let newest = {
+                    let user_data = user_data.borrow();
+                    let storage = user_data.storage();
+                    persist::load_newest_backup(storage.path(), |path| storage.read_backup(path))
+                };
+                match newest {
+                    Some((_, data)) => {
+                        user_data.borrow_mut().recover(data);
+                        set_page(CurrentPage::HomePage);
+                    }
+                    None => set_message(Some("None of the backups could be read".into())),
+                }
+            },
+            "Restore the newest working backup"
+        }))
+        backups.is_empty().then(|| rsx!(cx, p {"There are no backups."}))
+        ul {
+            backups.iter().map(|(time, path)| {
+                let age = format_age(*time);
+                let path = path.clone();
+                rsx!(cx, li {
+                    key: "{time}",
+                    "Backup from {age} "
+                    button {
+                        "type": "button",
+                        onclick: move |_| restore(&path),
+                        "Restore"
+                    }
+                })
+            })
+        }
+
+        // Only saves in the JSON format can be partly read
+        salvageable.then(|| rsx!(cx,
+            h2 {"Recover from the damaged file"}
+            button {
+                "type": "button",
+                onclick: move |_| {
+                    let result = damaged_file.as_deref()
+                        .map(|path| UserData::salvage(path).map_err(|e| e.to_string()));
+                    *salvaged.write() = result;
+                },
+                "See what can be recovered"
+            }
+            salvaged_preview
+        ))
+
+        h2 {"Start again"}
+        button {
+            "type": "button",
+            onclick: move |_| {
+                user_data.borrow_mut().recover(UserData::default());
+                set_page(CurrentPage::HomePage);
+            },
+            "Start over with the example sets"
+        }
+    })
+}
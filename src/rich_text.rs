@@ -0,0 +1,755 @@
+//! Formatted text - the document model behind [`RichText`], and the lightweight markup it's edited as:
+//!
+//! | Markup          | Formatting                                  |
+//! |-----------------|---------------------------------------------|
+//! | `**bold**`      | bold                                        |
+//! | `*italic*`      | italic                                      |
+//! | `__underline__` | underline                                   |
+//! | `~~strike~~`    | strikethrough                               |
+//! | `` `code` ``    | code - nothing inside is formatted          |
+//! | `^{super}`      | superscript                                 |
+//! | `_{sub}`        | subscript                                   |
+//! | `{red\|text}`   | coloured text                               |
+//! | `- item`        | bulleted list item, at the start of a line  |
+//! | `1. item`       | numbered list item, at the start of a line  |
+//!
+//! `\` in front of any character stops it being read as markup, and `{}` stands for nothing -
+//! it's written between markers that would otherwise run together.
+use std::{borrow::Cow, ops::Range};
+
+use dioxus::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data::{self, Color, RichText},
+    escape,
+};
+
+/// Raised or lowered text
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Script {
+    Normal,
+    Sub,
+    Super,
+}
+impl Default for Script {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+impl Script {
+    fn is_normal(&self) -> bool {
+        *self == Self::Normal
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// How a run of text is formatted
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Style {
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bold: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub italic: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub underline: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub strike: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub code: bool,
+    #[serde(default, skip_serializing_if = "Script::is_normal")]
+    pub script: Script,
+    /// Colour of this run, or `None` for the colour of the whole text
+    #[serde(default, deserialize_with = "data::color_or_none", skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+}
+
+/// A run of text with one style
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    #[serde(flatten)]
+    pub style: Style,
+}
+
+/// A line of text, or a list of them
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    Paragraph { spans: Vec<Span> },
+    /// Bulleted or numbered list, with the spans of each item
+    List { ordered: bool, items: Vec<Vec<Span>> },
+}
+
+/// Formatted text, as a list of paragraphs and lists
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct Document {
+    pub blocks: Vec<Block>,
+}
+
+/// Adds text to the end of `spans`, joining it onto the last span if that has the same style
+fn push_span(spans: &mut Vec<Span>, text: &str, style: &Style) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.style == *style => last.text.push_str(text),
+        _ => spans.push(Span {
+            text: text.into(),
+            style: style.clone(),
+        }),
+    }
+}
+
+/// What a `{` that's still open stands for, with the style to go back to when it's closed
+enum Brace {
+    Script(Script),
+    Color(Option<Color>),
+}
+
+/// If `line` starts a list item, whether it's numbered and the rest of the line
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    if let Some(item) = line.strip_prefix("- ") {
+        return Some((false, item));
+    }
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let item = line[digits..].strip_prefix(". ").filter(|_| digits > 0)?;
+    Some((true, item))
+}
+
+/// Reads the colour of a `{colour|text}` span from just after the `{`
+fn color_header(rest: &[char]) -> Option<(Color, usize)> {
+    let end = rest.iter().position(|c| *c == '|')?;
+    let color: String = rest[..end].iter().collect();
+    Some((Color::parse(&color)?, end))
+}
+
+/// Reads the formatting of one line of markup
+fn parse_inline(line: &str) -> Vec<Span> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut spans = vec![];
+    let mut style = Style::default();
+    let mut braces = vec![];
+    let mut text = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let next = chars.get(i + 1).copied();
+        if style.code {
+            // Nothing is formatted in code, up to the closing backtick
+            match (chars[i], next) {
+                ('\\', Some(escaped)) => {
+                    text.push(escaped);
+                    i += 2;
+                }
+                ('`', _) => {
+                    push_span(&mut spans, &text, &style);
+                    text.clear();
+                    style.code = false;
+                    i += 1;
+                }
+                (c, _) => {
+                    text.push(c);
+                    i += 1;
+                }
+            }
+            continue;
+        }
+        let before = style.clone();
+        let length = match (chars[i], next) {
+            ('\\', Some(escaped)) => {
+                text.push(escaped);
+                2
+            }
+            ('*', Some('*')) => {
+                style.bold = !style.bold;
+                2
+            }
+            ('*', _) => {
+                style.italic = !style.italic;
+                1
+            }
+            ('_', Some('_')) => {
+                style.underline = !style.underline;
+                2
+            }
+            ('~', Some('~')) => {
+                style.strike = !style.strike;
+                2
+            }
+            ('`', _) => {
+                style.code = true;
+                1
+            }
+            ('^', Some('{')) | ('_', Some('{')) => {
+                braces.push(Brace::Script(style.script));
+                style.script = if chars[i] == '^' { Script::Super } else { Script::Sub };
+                2
+            }
+            ('{', Some('}')) => 2,
+            ('{', _) => match color_header(&chars[i + 1..]) {
+                Some((color, length)) => {
+                    braces.push(Brace::Color(style.color.replace(color)));
+                    length + 2
+                }
+                None => {
+                    text.push('{');
+                    1
+                }
+            },
+            ('}', _) => match braces.pop() {
+                Some(Brace::Script(script)) => {
+                    style.script = script;
+                    1
+                }
+                Some(Brace::Color(color)) => {
+                    style.color = color;
+                    1
+                }
+                None => {
+                    text.push('}');
+                    1
+                }
+            },
+            (c, _) => {
+                text.push(c);
+                1
+            }
+        };
+        if style != before {
+            push_span(&mut spans, &text, &before);
+            text.clear();
+        }
+        i += length;
+    }
+    push_span(&mut spans, &text, &style);
+    spans
+}
+
+/// The markers that start and end a span's style, outermost first
+fn markers(style: &Style) -> (String, String) {
+    let mut open = String::new();
+    let mut close = vec![];
+    if let Some(color) = &style.color {
+        open.push_str(&format!("{{{}|", color));
+        close.push("}");
+    }
+    match style.script {
+        Script::Super => open.push_str("^{"),
+        Script::Sub => open.push_str("_{"),
+        Script::Normal => {}
+    }
+    if style.script != Script::Normal {
+        close.push("}");
+    }
+    for (on, marker) in [
+        (style.bold, "**"),
+        (style.italic, "*"),
+        (style.underline, "__"),
+        (style.strike, "~~"),
+        (style.code, "`"),
+    ] {
+        if on {
+            open.push_str(marker);
+            close.push(marker);
+        }
+    }
+    close.reverse();
+    (open, close.concat())
+}
+
+/// Writes text so none of it is read as markup
+fn escape(text: &str, code: bool, out: &mut String) {
+    let chars: Vec<char> = text.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        let next = chars.get(i + 1);
+        let escaped = if code {
+            matches!(c, '\\' | '`')
+        } else {
+            match c {
+                '\\' | '*' | '`' | '{' | '}' => true,
+                // These only mean something next to another character, which could be a marker after the span
+                '_' => matches!(next, None | Some('_' | '{')),
+                '~' => matches!(next, None | Some('~')),
+                '^' => matches!(next, None | Some('{')),
+                _ => false,
+            }
+        };
+        if escaped {
+            out.push('\\');
+        }
+        out.push(*c);
+    }
+}
+
+/// Writes one line of spans as markup
+fn write_inline(spans: &[Span]) -> String {
+    let mut out = String::new();
+    for span in spans {
+        let (open, close) = markers(&span.style);
+        // Keep the end of one span's markers apart from the start of the next's - `*` then `*` would read as `**`
+        if let (Some(last), Some(first)) = (out.chars().last(), open.chars().next()) {
+            if last == first && matches!(last, '*' | '_' | '~') {
+                out.push_str("{}");
+            }
+        }
+        out.push_str(&open);
+        escape(&span.text, span.style.code, &mut out);
+        out.push_str(&close);
+    }
+    out
+}
+
+impl Document {
+    /// Unformatted text, a paragraph per line
+    pub fn plain(text: &str) -> Self {
+        let blocks = text
+            .split('\n')
+            .map(|line| {
+                let mut spans = vec![];
+                push_span(&mut spans, line, &Style::default());
+                Block::Paragraph { spans }
+            })
+            .collect();
+        Self { blocks }
+    }
+    /// Reads markup
+    pub fn parse(markup: &str) -> Self {
+        let mut blocks: Vec<Block> = vec![];
+        for line in markup.split('\n') {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            match (list_item(line), blocks.last_mut()) {
+                (Some((ordered, item)), Some(Block::List { ordered: last, items })) if ordered == *last => {
+                    items.push(parse_inline(item));
+                }
+                (Some((ordered, item)), _) => blocks.push(Block::List {
+                    ordered,
+                    items: vec![parse_inline(item)],
+                }),
+                (None, _) => blocks.push(Block::Paragraph {
+                    spans: parse_inline(line),
+                }),
+            }
+        }
+        Self { blocks }
+    }
+    /// Writes the document as markup, which [`Document::parse`] reads back the same
+    pub fn markup(&self) -> String {
+        let mut lines = vec![];
+        for block in &self.blocks {
+            match block {
+                Block::Paragraph { spans } => {
+                    let mut line = write_inline(spans);
+                    // Keep paragraphs that start like list items from being read as them
+                    if let Some((ordered, _)) = list_item(&line) {
+                        let at = if ordered { line.find('.').unwrap_or(0) } else { 0 };
+                        line.insert(at, '\\');
+                    }
+                    lines.push(line);
+                }
+                Block::List { ordered, items } => {
+                    for (i, item) in items.iter().enumerate() {
+                        let marker = if *ordered { format!("{}. ", i + 1) } else { "- ".into() };
+                        lines.push(marker + &write_inline(item));
+                    }
+                }
+            }
+        }
+        lines.join("\n")
+    }
+    /// The spans of each line - one per paragraph or list item
+    pub fn lines(&self) -> impl Iterator<Item = &[Span]> {
+        self.blocks.iter().flat_map(|block| match block {
+            Block::Paragraph { spans } => vec![spans.as_slice()],
+            Block::List { items, .. } => items.iter().map(Vec::as_slice).collect(),
+        })
+    }
+    /// The text without its formatting, a line per paragraph or list item
+    pub fn plain_text(&self) -> String {
+        self.lines()
+            .map(|spans| spans.iter().map(|s| s.text.as_str()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    /// Every span, for changing the formatting of all of them
+    fn spans_mut(&mut self) -> impl Iterator<Item = &mut Span> {
+        self.blocks.iter_mut().flat_map(|block| match block {
+            Block::Paragraph { spans } => vec![spans],
+            Block::List { items, .. } => items.iter_mut().collect(),
+        })
+        .flatten()
+    }
+    /// Turns a style on for all of the text, or off if it's all on already
+    pub fn toggle(&mut self, style: fn(&mut Style) -> &mut bool) {
+        let all_on = self.spans_mut().all(|span| *style(&mut span.style));
+        for span in self.spans_mut() {
+            *style(&mut span.style) = !all_on;
+        }
+        self.join_spans();
+    }
+    /// Makes every line an item of a bulleted list, or a paragraph again if they all are already
+    pub fn toggle_list(&mut self) {
+        let lines: Vec<Vec<Span>> = self.lines().map(<[Span]>::to_vec).collect();
+        self.blocks = if self.blocks.iter().all(|b| matches!(b, Block::List { .. })) {
+            lines.into_iter().map(|spans| Block::Paragraph { spans }).collect()
+        } else {
+            vec![Block::List { ordered: false, items: lines }]
+        };
+    }
+    /// Joins neighbouring spans that have ended up with the same style
+    fn join_spans(&mut self) {
+        let join = |spans: &mut Vec<Span>| {
+            let mut joined = vec![];
+            for span in spans.drain(..) {
+                push_span(&mut joined, &span.text, &span.style);
+            }
+            *spans = joined;
+        };
+        for block in &mut self.blocks {
+            match block {
+                Block::Paragraph { spans } => join(spans),
+                Block::List { items, .. } => items.iter_mut().for_each(join),
+            }
+        }
+    }
+    /// Whether there's no formatting at all - just lines of text
+    pub fn is_plain(&self) -> bool {
+        self.blocks.iter().all(|block| match block {
+            Block::Paragraph { spans } => spans.iter().all(|s| s.style == Style::default()),
+            Block::List { .. } => false,
+        })
+    }
+}
+
+/// Whether `second` written straight after `first` would run into it, like `*` then `*` reading as `**`
+fn runs_together(first: &str, second: &str) -> bool {
+    match (first.chars().last(), second.chars().next()) {
+        (Some(a), Some(b)) => a == b && matches!(a, '*' | '_' | '~'),
+        _ => false,
+    }
+}
+
+/// The parts of each line of `markup` in `selection`, leaving out list markers
+fn selected_runs(markup: &str, selection: &Range<usize>) -> Vec<Range<usize>> {
+    let mut runs = vec![];
+    let mut line_start = 0;
+    for line in markup.split('\n') {
+        let line_end = line_start + line.len();
+        let mut start = selection.start.max(line_start);
+        let end = selection.end.min(line_end);
+        if let Some((_, item)) = list_item(line) {
+            start = start.max(line_end - item.len());
+        }
+        if start < end {
+            runs.push(start..end);
+        }
+        line_start = line_end + 1;
+    }
+    runs
+}
+
+/// Puts the markers for `style` around the selected part of `markup`, or takes them away if it's wrapped in them already.
+/// Each line is wrapped on its own, as formatting doesn't carry across lines.
+pub fn format_selection(markup: &str, selection: Range<usize>, style: &Style) -> String {
+    let (open, close) = markers(style);
+    let runs = selected_runs(markup, &selection);
+    // Where the markers to take away are - inside the selection, or just outside it
+    let wrapped = |run: &Range<usize>| {
+        let text = &markup[run.clone()];
+        if text.len() >= open.len() + close.len() && text.starts_with(&open) && text.ends_with(&close) {
+            return Some((run.start..run.start + open.len(), run.end - close.len()..run.end));
+        }
+        let before = markup[..run.start].strip_suffix(open.as_str())?;
+        let after = markup[run.end..].strip_prefix(close.as_str())?;
+        // Only half of a longer marker, like the `*` of `**`
+        if runs_together(before, &open) || runs_together(&close, after) {
+            return None;
+        }
+        Some((before.len()..run.start, run.end..run.end + close.len()))
+    };
+    let unwrapping: Option<Vec<_>> = runs.iter().map(wrapped).collect();
+
+    let mut out = String::new();
+    let mut done = 0;
+    match unwrapping {
+        Some(markers) if !markers.is_empty() => {
+            for (open, close) in markers {
+                out.push_str(&markup[done..open.start]);
+                out.push_str(&markup[open.end..close.start]);
+                done = close.end;
+            }
+        }
+        _ => {
+            for run in runs {
+                out.push_str(&markup[done..run.start]);
+                if runs_together(&out, &open) {
+                    out.push_str("{}");
+                }
+                out.push_str(&open);
+                let text = &markup[run.clone()];
+                if runs_together(&open, text) {
+                    out.push_str("{}");
+                }
+                out.push_str(text);
+                if runs_together(text, &close) {
+                    out.push_str("{}");
+                }
+                out.push_str(&close);
+                if runs_together(&close, &markup[run.end..]) {
+                    out.push_str("{}");
+                }
+                done = run.end;
+            }
+        }
+    }
+    out.push_str(&markup[done..]);
+    out
+}
+
+/// Makes the lines of `markup` in `selection` items of a bulleted list, or paragraphs again if they all are already
+pub fn toggle_list_lines(markup: &str, selection: Range<usize>) -> String {
+    let mut lines = vec![];
+    let mut line_start = 0;
+    for line in markup.split('\n') {
+        let line_end = line_start + line.len();
+        lines.push((line, line_start < selection.end && selection.start <= line_end));
+        line_start = line_end + 1;
+    }
+    let all_items = lines.iter().filter(|(_, selected)| *selected).all(|(line, _)| list_item(line).is_some());
+    lines
+        .into_iter()
+        .map(|(line, selected)| match (selected, list_item(line)) {
+            (false, _) => line.to_owned(),
+            (true, Some((_, item))) if all_items => item.to_owned(),
+            (true, Some(_)) => line.to_owned(),
+            (true, None) => format!("- {}", line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl RichText {
+    /// Text with formatting, in `color` where the formatting doesn't give another colour
+    pub fn formatted(doc: Document, color: Color) -> Self {
+        let mut text = Self { color, ..Self::empty() };
+        text.set_document(doc);
+        text
+    }
+    /// Replaces the text and its formatting, keeping its colour and images
+    pub fn set_document(&mut self, doc: Document) {
+        self.text = doc.plain_text();
+        // Plain text is kept as it always was
+        self.doc = if doc.is_plain() { None } else { Some(doc) };
+    }
+    /// Reads text written in markup
+    pub fn from_markup(markup: &str, color: Color) -> Self {
+        Self::formatted(Document::parse(markup), color)
+    }
+    /// The formatting of this text - text saved without any is all plain paragraphs
+    pub fn document(&self) -> Cow<'_, Document> {
+        match &self.doc {
+            Some(doc) => Cow::Borrowed(doc),
+            None => Cow::Owned(Document::plain(&self.text)),
+        }
+    }
+    /// Changes the formatting, keeping the plain text in step
+    pub fn change_document(&mut self, change: impl FnOnce(&mut Document)) {
+        let mut doc = self.document().into_owned();
+        change(&mut doc);
+        self.set_document(doc);
+    }
+    /// The text as markup, for editing
+    pub fn markup(&self) -> String {
+        self.document().markup()
+    }
+}
+
+/// The CSS classes and inline style showing a span's formatting
+fn span_attributes(style: &Style) -> (String, String) {
+    let classes: Vec<&str> = [
+        (style.bold, "rich-bold"),
+        (style.italic, "rich-italic"),
+        (style.underline, "rich-underline"),
+        (style.strike, "rich-strike"),
+        (style.code, "rich-code"),
+        (style.script == Script::Sub, "rich-sub"),
+        (style.script == Script::Super, "rich-super"),
+    ]
+    .iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, class)| *class)
+    .collect();
+    (classes.join(" "), escape::color_style(style.color.as_ref()))
+}
+
+#[derive(Props, PartialEq)]
+pub struct RichTextViewProps {
+    text: RichText,
+}
+/// Shows formatted text
+pub fn RichTextView(cx: Scope<RichTextViewProps>) -> Element {
+    let text = &cx.props.text;
+    let doc = text.document();
+    let css = escape::color_style(Some(&text.color));
+    let spans = |spans: &[Span]| {
+        spans.iter().enumerate().map(|(i, span)| {
+            let (class, css) = span_attributes(&span.style);
+            rsx!(cx, span { key: "{i}", class: "{class}", style: "{css}", "{span.text}" })
+        }).collect::<Vec<_>>()
+    };
+    let blocks = doc.blocks.iter().enumerate().map(|(i, block)| match block {
+        Block::Paragraph { spans: line } => rsx!(cx, p { key: "{i}", spans(line) }),
+        Block::List { ordered, items } => {
+            let items = items.iter().enumerate().map(|(j, item)| rsx!(cx, li { key: "{j}", spans(item) }));
+            if *ordered {
+                rsx!(cx, ol { key: "{i}", items })
+            } else {
+                rsx!(cx, ul { key: "{i}", items })
+            }
+        }
+    });
+    rsx!(cx, div {
+        class: "rich-text",
+        style: "{css}",
+        blocks
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(markup: &str) {
+        let doc = Document::parse(markup);
+        let written = doc.markup();
+        assert_eq!(Document::parse(&written), doc, "{:?} was written as {:?}", markup, written);
+    }
+
+    #[test]
+    fn reads_inline_formatting() {
+        let doc = Document::parse("H_{2}O is **very** {red|wet}");
+        let spans = doc.lines().next().unwrap();
+        let texts: Vec<&str> = spans.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["H", "2", "O is ", "very", " ", "wet"]);
+        assert_eq!(spans[1].style.script, Script::Sub);
+        assert!(spans[3].style.bold);
+        assert_eq!(spans[5].style.color, Color::parse("red"));
+        assert_eq!(doc.plain_text(), "H2O is very wet");
+    }
+
+    #[test]
+    fn reads_lists() {
+        let doc = Document::parse("Steps:\n1. one\n2. two\n- bullet");
+        assert_eq!(doc.blocks.len(), 3);
+        assert!(matches!(&doc.blocks[1], Block::List { ordered: true, items } if items.len() == 2));
+        assert!(matches!(&doc.blocks[2], Block::List { ordered: false, .. }));
+        assert_eq!(doc.plain_text(), "Steps:\none\ntwo\nbullet");
+    }
+
+    #[test]
+    fn plain_text_has_no_document() {
+        let text = RichText::from_markup("just words\nover lines", Color::black());
+        assert_eq!(text.doc, None);
+        assert_eq!(text, RichText::plaintext("just words\nover lines".into()));
+        assert!(RichText::from_markup("*some* words", Color::black()).doc.is_some());
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        for text in ["2*3*4", "a_b", "snake__case", "x^2", "{not|colour}", "~~", "- not a list", "1. nor this", "\\"] {
+            let rich = RichText::plaintext(text.into());
+            assert_eq!(RichText::from_markup(&rich.markup(), rich.color.clone()), rich, "{}", rich.markup());
+        }
+    }
+
+    #[test]
+    fn only_colours_are_colours() {
+        let doc = Document::parse("{red; background: url(x)|text}");
+        assert_eq!(doc.plain_text(), "{red; background: url(x)|text}");
+        let json = r#"[{"type": "paragraph", "spans": [{"text": "a", "color": "url(x)"}, {"text": "b", "color": "Red"}]}]"#;
+        let doc: Document = serde_json::from_str(json).unwrap();
+        assert_eq!(doc.lines().next().unwrap()[0].style.color, None);
+        assert_eq!(doc.lines().next().unwrap()[1].style.color, Color::parse("red"));
+    }
+
+    #[test]
+    fn round_trips_markup() {
+        for markup in [
+            "**bold***italic*",
+            "***both*** __under__~~strike~~",
+            "*a*{}*b* `co*de` ^{up}_{down}",
+            "{#ff0000|red **and bold**}{blue|blue}",
+            "{rgb(1, 2, 3)|x_{2}} y",
+            "- one\n- *two*\n\n1. three\n10. four",
+            "unclosed **bold and _{sub",
+            "__a___{b}",
+            "stray } and { braces",
+        ] {
+            assert_round_trip(markup);
+        }
+    }
+
+    #[test]
+    fn formats_the_selection() {
+        let bold = Style { bold: true, ..Style::default() };
+        let italic = Style { italic: true, ..Style::default() };
+        let red = Style { color: Color::parse("#c0392b"), ..Style::default() };
+        assert_eq!(format_selection("one two three", 4..7, &bold), "one **two** three");
+        assert_eq!(format_selection("one **two** three", 6..9, &bold), "one two three");
+        assert_eq!(format_selection("one **two** three", 4..11, &bold), "one two three");
+        assert_eq!(format_selection("one two", 4..7, &red), "one {#c0392b|two}");
+        assert_eq!(format_selection("one {#c0392b|two}", 13..16, &red), "one two");
+        // Italic inside bold is added, not mistaken for half of the bold
+        assert_eq!(format_selection("**bold**", 2..6, &italic), "**{}*bold*{}**");
+        assert_eq!(
+            Document::parse(&format_selection("**bold**", 2..6, &italic)).lines().next().unwrap()[0].style,
+            Style { bold: true, italic: true, ..Style::default() }
+        );
+        // Lines are wrapped one by one, leaving out list markers
+        assert_eq!(format_selection("- one\n- two", 0..11, &bold), "- **one**\n- **two**");
+        assert_eq!(format_selection("- **one**\n- **two**", 0..19, &bold), "- one\n- two");
+        assert_eq!(format_selection("a\n\nb", 0..4, &italic), "*a*\n\n*b*");
+    }
+
+    #[test]
+    fn toggles_the_selected_lines_as_a_list() {
+        assert_eq!(toggle_list_lines("one\ntwo\nthree", 1..5), "- one\n- two\nthree");
+        assert_eq!(toggle_list_lines("- one\n- two\nthree", 0..9), "one\ntwo\nthree");
+        assert_eq!(toggle_list_lines("- one\ntwo", 0..9), "- one\n- two");
+        // A selection that ends at the start of a line leaves that line alone
+        assert_eq!(toggle_list_lines("one\ntwo", 0..4), "- one\ntwo");
+    }
+
+    #[test]
+    fn round_trips_every_pair_of_styles() {
+        let styles = [
+            Style::default(),
+            Style { bold: true, ..Style::default() },
+            Style { italic: true, ..Style::default() },
+            Style { bold: true, italic: true, ..Style::default() },
+            Style { underline: true, ..Style::default() },
+            Style { strike: true, ..Style::default() },
+            Style { code: true, ..Style::default() },
+            Style { script: Script::Sub, underline: true, ..Style::default() },
+            Style { script: Script::Super, ..Style::default() },
+            Style { color: Color::parse("red"), ..Style::default() },
+        ];
+        for first in &styles {
+            for second in &styles {
+                for (a, b) in [("a", "b"), ("_", "_"), ("*", "~"), ("^", "{")] {
+                    let mut spans = vec![];
+                    push_span(&mut spans, a, first);
+                    push_span(&mut spans, b, second);
+                    let doc = Document {
+                        blocks: vec![Block::Paragraph { spans }],
+                    };
+                    assert_eq!(Document::parse(&doc.markup()), doc, "{:?}", doc.markup());
+                }
+            }
+        }
+    }
+}
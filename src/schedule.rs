@@ -0,0 +1,304 @@
+//! Spaced-repetition scheduling (SM-2) - decides when each flashcard should be reviewed
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::FlashcardSet;
+
+/// Seconds in a day
+pub const DAY: u64 = 60 * 60 * 24;
+/// How long until a forgotten card is shown again (seconds)
+const RELEARN_DELAY: u64 = 60 * 10;
+/// Starting ease of a card (per mille, like anki)
+const STARTING_EASE: u32 = 2500;
+/// Lowest the ease of a card can fall to
+const MIN_EASE: u32 = 1300;
+/// Highest the ease of a card can rise to
+const MAX_EASE: u32 = 5000;
+/// Longest interval between reviews (days) - about a hundred years
+const MAX_INTERVAL: u32 = 36500;
+/// How many never-seen cards are introduced in one session
+pub const NEW_CARDS_PER_SESSION: usize = 20;
+
+/// Current time in seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// How well the user remembered a card
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grade {
+    /// Forgotten
+    Again,
+    /// Remembered with difficulty
+    Hard,
+    /// Remembered
+    Good,
+    /// Remembered effortlessly
+    Easy,
+}
+impl Grade {
+    /// Every grade, from worst to best
+    pub const ALL: [Self; 4] = [Self::Again, Self::Hard, Self::Good, Self::Easy];
+}
+
+/// Which face of a card is shown as the prompt
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Shows the front, asks for the back
+    Forward,
+    /// Shows the back, asks for the front
+    Reverse,
+}
+impl Default for Direction {
+    fn default() -> Self {
+        Self::Forward
+    }
+}
+
+/// Which directions a set or session is studied in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StudyDirection {
+    Forward,
+    Reverse,
+    Both,
+}
+impl Default for StudyDirection {
+    fn default() -> Self {
+        Self::Forward
+    }
+}
+impl StudyDirection {
+    pub const ALL: [Self; 3] = [Self::Forward, Self::Reverse, Self::Both];
+    /// The directions cards are studied in
+    pub const fn directions(self) -> &'static [Direction] {
+        match self {
+            Self::Forward => &[Direction::Forward],
+            Self::Reverse => &[Direction::Reverse],
+            Self::Both => &[Direction::Forward, Direction::Reverse],
+        }
+    }
+    /// Short name, used as a form value
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Forward => "forward",
+            Self::Reverse => "reverse",
+            Self::Both => "both",
+        }
+    }
+    /// Parses a [`StudyDirection::name`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|d| d.name() == name)
+    }
+    /// Human readable description
+    pub const fn label(self) -> &'static str {
+        match self {
+            Self::Forward => "Front → back",
+            Self::Reverse => "Back → front",
+            Self::Both => "Both ways",
+        }
+    }
+}
+
+/// A card to be studied in one direction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prompt {
+    /// Id of the card
+    pub card: u64,
+    pub direction: Direction,
+}
+
+/// A record of one review of a card
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReviewLog {
+    /// When the review happened (seconds since the unix epoch)
+    pub time: u64,
+    pub grade: Grade,
+    /// Which way round the card was studied
+    #[serde(default)]
+    pub direction: Direction,
+    /// Interval before the review
+    pub last_interval: u32,
+    /// Interval after the review
+    pub interval: u32,
+    /// Ease after the review
+    pub ease: u32,
+}
+impl ReviewLog {
+    /// Whether the card was remembered
+    pub fn correct(&self) -> bool {
+        self.grade != Grade::Again
+    }
+}
+
+/// The review state of a single flashcard
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReviewState {
+    /// How quickly the interval grows (per mille - 2500 is x2.5)
+    pub ease: u32,
+    /// Current interval between reviews in days (0 while learning)
+    pub interval: u32,
+    /// When the card is next due (seconds since the unix epoch)
+    pub due: u64,
+    /// How many times the card has been forgotten after being learnt
+    pub lapses: u32,
+    /// How many times the card has been reviewed
+    pub reviews: u32,
+}
+impl Default for ReviewState {
+    fn default() -> Self {
+        Self {
+            ease: STARTING_EASE,
+            interval: 0,
+            due: 0,
+            lapses: 0,
+            reviews: 0,
+        }
+    }
+}
+impl ReviewState {
+    /// Whether this card has never been reviewed
+    pub const fn is_new(&self) -> bool {
+        self.reviews == 0
+    }
+    /// Whether this card should be reviewed at `now`
+    pub const fn is_due(&self, now: u64) -> bool {
+        !self.is_new() && self.due <= now
+    }
+    /// Updates this state after a review at `now` with the given grade, returning a log of the review
+    pub fn review(&mut self, direction: Direction, grade: Grade, now: u64) -> ReviewLog {
+        let last_interval = self.interval;
+        self.schedule(grade, now);
+        ReviewLog {
+            time: now,
+            grade,
+            direction,
+            last_interval,
+            interval: self.interval,
+            ease: self.ease,
+        }
+    }
+    /// Moves the due date according to the grade
+    fn schedule(&mut self, grade: Grade, now: u64) {
+        self.reviews = self.reviews.saturating_add(1);
+        // Imported or hand-edited data can be out of range
+        self.ease = self.ease.clamp(MIN_EASE, MAX_EASE);
+        self.interval = self.interval.min(MAX_INTERVAL);
+        match grade {
+            Grade::Again => {
+                if self.interval > 0 {
+                    self.lapses = self.lapses.saturating_add(1);
+                }
+                self.ease = self.ease.saturating_sub(200).max(MIN_EASE);
+                self.interval = 0;
+                self.due = now.saturating_add(RELEARN_DELAY);
+                return;
+            }
+            Grade::Hard => {
+                self.ease = self.ease.saturating_sub(150).max(MIN_EASE);
+                self.interval = scale(self.interval, 1200).max(1);
+            }
+            Grade::Good => {
+                self.interval = match self.interval {
+                    0 => 1,
+                    i => scale(i, u64::from(self.ease)).max(i + 1),
+                };
+            }
+            Grade::Easy => {
+                self.interval = match self.interval {
+                    0 => 4,
+                    i => scale(i, u64::from(self.ease) * 13 / 10).max(i + 1),
+                };
+                self.ease = (self.ease + 150).min(MAX_EASE);
+            }
+        }
+        self.interval = self.interval.min(MAX_INTERVAL);
+        self.due = now.saturating_add(u64::from(self.interval) * DAY);
+    }
+}
+
+/// Multiplies an interval by `factor` (per mille), without going over [`MAX_INTERVAL`]
+fn scale(interval: u32, factor: u64) -> u32 {
+    let scaled = u64::from(interval) * factor / 1000;
+    scaled.min(u64::from(MAX_INTERVAL)) as u32
+}
+
+/// Picks the cards in `set` that should be studied at `now` in the given directions.
+/// Due cards come first (most overdue first), followed by up to `new_limit` new cards.
+pub fn due_cards(set: &FlashcardSet, direction: StudyDirection, now: u64, new_limit: usize) -> Vec<Prompt> {
+    let prompts = || {
+        direction.directions().iter().flat_map(|&direction| {
+            set.flashcards.iter().map(move |f| (f.review_state(direction), Prompt {
+                card: f.id(),
+                direction,
+            }))
+        })
+    };
+    let mut due: Vec<_> = prompts().filter(|(r, _)| r.is_due(now)).collect();
+    due.sort_by_key(|(r, _)| r.due);
+    let new = prompts().filter(|(r, _)| r.is_new()).take(new_limit);
+    due.into_iter().chain(new).map(|(_, p)| p).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The state after reviewing a new card with each of `grades`, a day apart
+    fn after(grades: &[Grade]) -> ReviewState {
+        let mut state = ReviewState::default();
+        for (day, &grade) in grades.iter().enumerate() {
+            state.review(Direction::Forward, grade, day as u64 * DAY);
+        }
+        state
+    }
+
+    #[test]
+    fn schedules_like_sm2() {
+        assert_eq!(after(&[Grade::Good]).interval, 1);
+        assert_eq!(after(&[Grade::Easy]).interval, 4);
+        // 1 day, then x2.5 rounded down, but always at least a day longer
+        let good = after(&[Grade::Good, Grade::Good, Grade::Good]);
+        assert_eq!(good.interval, 5);
+        assert_eq!(good.ease, STARTING_EASE);
+        assert_eq!(good.due, 2 * DAY + 5 * DAY);
+        let hard = after(&[Grade::Good, Grade::Hard]);
+        assert_eq!(hard.interval, 1);
+        assert_eq!(hard.ease, STARTING_EASE - 150);
+    }
+
+    #[test]
+    fn forgetting_resets_the_interval() {
+        let state = after(&[Grade::Good, Grade::Good, Grade::Again]);
+        assert_eq!(state.interval, 0);
+        assert_eq!(state.lapses, 1);
+        assert_eq!(state.ease, STARTING_EASE - 200);
+        assert_eq!(state.due, 2 * DAY + RELEARN_DELAY);
+        assert!(!state.is_new());
+        // Forgetting while still learning isn't a lapse
+        assert_eq!(after(&[Grade::Again]).lapses, 0);
+        assert_eq!(after(&[Grade::Again; 20]).ease, MIN_EASE);
+    }
+
+    #[test]
+    fn intervals_and_ease_stay_in_range() {
+        let state = after(&[Grade::Easy; 100]);
+        assert_eq!(state.interval, MAX_INTERVAL);
+        assert_eq!(state.ease, MAX_EASE);
+        // Values that would overflow a u32 when multiplied
+        let mut state = ReviewState {
+            ease: u32::MAX,
+            interval: u32::MAX,
+            ..ReviewState::default()
+        };
+        for grade in [Grade::Easy, Grade::Good, Grade::Hard] {
+            state.review(Direction::Forward, grade, u64::MAX);
+            assert_eq!(state.interval, MAX_INTERVAL);
+            assert!(state.ease <= MAX_EASE);
+            assert_eq!(state.due, u64::MAX);
+        }
+    }
+}
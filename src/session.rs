@@ -0,0 +1,256 @@
+//! The state machine behind a single study session
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::schedule::{Grade, Prompt};
+
+/// What the user is currently being shown
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Phase {
+    /// Only the front of the current card is showing
+    Question,
+    /// The back has been revealed and the card is waiting for a grade
+    Answer,
+    /// There are no cards left
+    Finished,
+}
+
+/// What happened during a session, shown once it ends
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct SessionSummary {
+    /// Number of grades given for each of [`Grade::ALL`]
+    pub grades: [u32; 4],
+    /// Cards that were failed at least once
+    pub failed: Vec<Prompt>,
+}
+impl SessionSummary {
+    /// Total number of grades given
+    pub fn reviews(&self) -> u32 {
+        self.grades.iter().sum()
+    }
+    /// Number of times `grade` was given
+    pub fn count(&self, grade: Grade) -> u32 {
+        self.grades[grade as usize]
+    }
+}
+
+/// A study session - a queue of cards, shown one at a time until every card is remembered
+/// or the time budget runs out
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct StudySession {
+    queue: VecDeque<Prompt>,
+    phase: Phase,
+    summary: SessionSummary,
+    /// When the session started
+    started: Instant,
+    /// After this no new cards are shown
+    deadline: Instant,
+    /// When the session finished
+    ended: Option<Instant>,
+    /// Whether the time spent has been taken by [`StudySession::take_time_spent`]
+    time_taken: bool,
+}
+impl StudySession {
+    /// Starts a session over the given cards, in order, lasting at most `budget`
+    pub fn new(queue: Vec<Prompt>, budget: Duration) -> Self {
+        let started = Instant::now();
+        let mut this = Self {
+            queue: queue.into(),
+            phase: Phase::Question,
+            summary: SessionSummary::default(),
+            started,
+            deadline: started + budget,
+            ended: None,
+            time_taken: false,
+        };
+        if this.queue.is_empty() {
+            this.finish();
+        }
+        this
+    }
+    /// The card currently being shown
+    pub fn current(&self) -> Option<Prompt> {
+        self.queue.front().copied()
+    }
+    pub const fn phase(&self) -> Phase {
+        self.phase
+    }
+    pub const fn summary(&self) -> &SessionSummary {
+        &self.summary
+    }
+    /// Number of cards still waiting in the queue (including the current one)
+    pub fn remaining(&self) -> usize {
+        self.queue.len()
+    }
+    /// Reveals the back of the current card
+    pub fn reveal(&mut self) {
+        if self.phase == Phase::Question {
+            self.phase = Phase::Answer;
+        }
+    }
+    /// Grades the current card and moves on to the next one.
+    /// Failed cards are put back at the end of the queue.
+    /// Returns the card that was graded.
+    pub fn grade(&mut self, grade: Grade) -> Option<Prompt> {
+        if self.phase != Phase::Answer {
+            return None;
+        }
+        let id = self.queue.pop_front()?;
+        self.summary.grades[grade as usize] += 1;
+        if grade == Grade::Again {
+            if !self.summary.failed.contains(&id) {
+                self.summary.failed.push(id);
+            }
+            self.queue.push_back(id);
+        }
+        if self.queue.is_empty() || self.is_out_of_time() {
+            self.finish();
+        } else {
+            self.phase = Phase::Question;
+        }
+        Some(id)
+    }
+    /// Ends the session
+    pub fn finish(&mut self) {
+        self.phase = Phase::Finished;
+        self.ended.get_or_insert_with(Instant::now);
+    }
+    /// When the session started
+    pub const fn started(&self) -> Instant {
+        self.started
+    }
+    /// Time left before no more cards are shown
+    pub fn time_left(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+    /// Whether the time budget has run out
+    pub fn is_out_of_time(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+    /// How long has been spent studying in this session
+    pub fn time_spent(&self) -> Duration {
+        self.ended.unwrap_or_else(Instant::now) - self.started
+    }
+    /// The total time spent, once the session has finished.
+    /// Only returns it once, so it can be added to the user's study time.
+    pub fn take_time_spent(&mut self) -> Option<Duration> {
+        if self.phase != Phase::Finished || self.time_taken {
+            return None;
+        }
+        self.time_taken = true;
+        Some(self.time_spent())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::Direction;
+
+    fn prompts(count: u64) -> Vec<Prompt> {
+        (0..count).map(|card| Prompt { card, direction: Direction::Forward }).collect()
+    }
+
+    fn session(count: u64) -> StudySession {
+        StudySession::new(prompts(count), Duration::from_secs(600))
+    }
+
+    #[test]
+    fn reveals_before_grading() {
+        let mut session = session(2);
+        assert_eq!(session.phase(), Phase::Question);
+        assert_eq!(session.grade(Grade::Good), None);
+        assert_eq!(session.summary().reviews(), 0);
+        session.reveal();
+        assert_eq!(session.phase(), Phase::Answer);
+        assert_eq!(session.grade(Grade::Good), Some(prompts(2)[0]));
+        assert_eq!(session.phase(), Phase::Question);
+        assert_eq!(session.current(), Some(prompts(2)[1]));
+    }
+
+    #[test]
+    fn forgotten_cards_come_back_at_the_end() {
+        let mut session = session(2);
+        let [first, second]: [Prompt; 2] = prompts(2).try_into().unwrap();
+        session.reveal();
+        session.grade(Grade::Again);
+        assert_eq!(session.current(), Some(second));
+        assert_eq!(session.remaining(), 2);
+        session.reveal();
+        session.grade(Grade::Good);
+        assert_eq!(session.current(), Some(first));
+        // Forgotten again, but only listed once
+        session.reveal();
+        session.grade(Grade::Again);
+        assert_eq!(session.current(), Some(first));
+        session.reveal();
+        session.grade(Grade::Hard);
+        assert_eq!(session.summary().failed, [first]);
+    }
+
+    #[test]
+    fn finishes_when_the_queue_is_empty() {
+        let mut session = session(1);
+        session.reveal();
+        session.grade(Grade::Easy);
+        assert_eq!(session.phase(), Phase::Finished);
+        assert_eq!(session.current(), None);
+        assert_eq!(session.remaining(), 0);
+        // Nothing more to reveal or grade
+        session.reveal();
+        assert_eq!(session.phase(), Phase::Finished);
+        assert_eq!(session.grade(Grade::Good), None);
+    }
+
+    #[test]
+    fn nothing_to_study_is_finished_at_once() {
+        let session = session(0);
+        assert_eq!(session.phase(), Phase::Finished);
+        assert_eq!(session.current(), None);
+    }
+
+    #[test]
+    fn counts_each_grade() {
+        let mut session = session(3);
+        for grade in [Grade::Again, Grade::Good, Grade::Easy, Grade::Hard] {
+            session.reveal();
+            session.grade(grade);
+        }
+        let summary = session.summary();
+        assert_eq!(summary.reviews(), 4);
+        for grade in Grade::ALL {
+            assert_eq!(summary.count(grade), 1, "{:?}", grade);
+        }
+        assert_eq!(session.phase(), Phase::Finished);
+    }
+
+    #[test]
+    fn a_zero_budget_finishes_on_the_next_grade() {
+        let mut session = StudySession::new(prompts(3), Duration::ZERO);
+        assert!(session.is_out_of_time());
+        assert_eq!(session.time_left(), Duration::ZERO);
+        // The card on screen can still be answered
+        assert_eq!(session.phase(), Phase::Question);
+        session.reveal();
+        session.grade(Grade::Good);
+        assert_eq!(session.phase(), Phase::Finished);
+        assert_eq!(session.remaining(), 2);
+    }
+
+    #[test]
+    fn time_spent_is_taken_once_finished() {
+        let mut session = session(2);
+        assert!(!session.is_out_of_time());
+        assert!(session.time_left() > Duration::ZERO);
+        assert_eq!(session.take_time_spent(), None);
+        session.reveal();
+        session.grade(Grade::Good);
+        assert_eq!(session.take_time_spent(), None);
+        session.finish();
+        let spent = session.take_time_spent().unwrap();
+        assert_eq!(spent, session.time_spent());
+        assert_eq!(session.take_time_spent(), None);
+    }
+}
@@ -0,0 +1,596 @@
+//! Where the user data is kept - a JSON file, or an SQLite database
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension};
+use serde_json::{json, Value};
+
+use crate::{
+    data::{LoadFailure, SetId, UserData},
+    persist,
+};
+
+/// Environment variable choosing the storage backend - `json` (the default) or `sqlite`
+const STORAGE_VAR: &str = "MAGISTRAX_STORAGE";
+
+/// Somewhere [`UserData`] can be loaded from and saved to
+pub trait Storage: Send {
+    /// Loads the user's data. If it's damaged it's moved aside, so it won't be saved over.
+    fn load(&mut self) -> Result<UserData, LoadFailure>;
+    /// Saves all of the data
+    fn save(&mut self, data: &UserData) -> io::Result<()>;
+    /// Saves the set with this id after it's been added or changed, along with the set order
+    fn save_set(&mut self, data: &UserData, _set: SetId) -> io::Result<()> {
+        self.save(data)
+    }
+    /// Saves these cards of the set with this id after they've been changed (not added, removed or moved)
+    fn save_cards(&mut self, data: &UserData, set: SetId, _cards: &[u64]) -> io::Result<()> {
+        self.save_set(data, set)
+    }
+    /// Deletes the set with this id, which has been removed from `data`
+    fn remove_set(&mut self, data: &UserData, _set: SetId) -> io::Result<()> {
+        self.save(data)
+    }
+    /// Saves everything but the sets - study time and so on
+    fn save_meta(&mut self, data: &UserData) -> io::Result<()> {
+        self.save(data)
+    }
+    /// The file the data is kept in (its backups are kept next to it)
+    fn path(&self) -> &Path;
+    /// Reads a backup of the data file
+    fn read_backup(&self, path: &Path) -> io::Result<UserData>;
+}
+
+/// Opens the data kept in `dir`, with the storage backend chosen by the `MAGISTRAX_STORAGE` environment variable
+pub fn open(dir: &Path) -> Box<dyn Storage> {
+    let json = JsonStorage::new(dir.join("user-data.json"));
+    match env::var(STORAGE_VAR).as_deref() {
+        Ok("sqlite") => Box::new(SqliteStorage::new(dir.join("user-data.sqlite3"), json)),
+        Ok("json") | Err(_) => Box::new(json),
+        Ok(other) => {
+            warn!("Unknown storage {:?}, using json", other);
+            Box::new(json)
+        }
+    }
+}
+
+/// Folder the images and sounds belonging to the data file at `path` are kept in
+pub fn media_dir(path: &Path) -> PathBuf {
+    path.with_file_name("media")
+}
+
+/// Whether an error means the data itself is damaged (rather than e.g. locked)
+fn is_damaged(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof)
+}
+
+/// Builds a [`LoadFailure`], moving the file aside if it's damaged
+fn load_failure(path: &Path, e: io::Error) -> LoadFailure {
+    error!("Could not load user data: {}", e);
+    let quarantined = if is_damaged(&e) {
+        persist::quarantine(path)
+            .map_err(|e| error!("Could not move damaged user data aside: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    LoadFailure {
+        error: e.to_string(),
+        quarantined,
+    }
+}
+
+/// Fails if there's no data at `path` because it was damaged and moved aside, and hasn't been recovered yet.
+/// Starting again with the example sets then would lose it for good once they were saved and backed up.
+fn check_not_quarantined(path: &Path) -> Result<(), LoadFailure> {
+    match persist::quarantined(path).into_iter().next() {
+        Some(quarantined) => {
+            error!("User data was moved aside as damaged and hasn't been recovered yet");
+            Err(LoadFailure {
+                error: "your data was found to be damaged, and hasn't been recovered yet".into(),
+                quarantined: Some(quarantined),
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Keeps everything in one JSON file, rewritten in full on every save
+pub struct JsonStorage {
+    path: PathBuf,
+}
+impl JsonStorage {
+    pub const fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+impl Storage for JsonStorage {
+    fn load(&mut self) -> Result<UserData, LoadFailure> {
+        info!("Loading user data");
+        // Attempt to load this file
+        if self.path.exists() {
+            UserData::load_from(&self.path).map_err(|e| load_failure(&self.path, e))
+        } else {
+            check_not_quarantined(&self.path)?;
+            let data = UserData::default();
+            self.save(&data).map_err(|e| load_failure(&self.path, e))?;
+            Ok(data)
+        }
+    }
+    fn save(&mut self, data: &UserData) -> io::Result<()> {
+        info!("Saving user data");
+        // Ensure all folders exist
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Keep a copy of the last file before replacing it
+        if let Err(e) = persist::backup(&self.path) {
+            warn!("Could not back up user data: {}", e);
+        }
+        persist::write_atomic(&self.path, &serde_json::to_vec(data)?)
+    }
+    fn path(&self) -> &Path {
+        &self.path
+    }
+    fn read_backup(&self, path: &Path) -> io::Result<UserData> {
+        UserData::load_from(path)
+    }
+}
+
+/// Used when there's nowhere to keep the data - nothing can be loaded or saved
+pub struct Unavailable {
+    error: String,
+}
+impl Unavailable {
+    pub fn new(error: io::Error) -> Self {
+        Self {
+            error: error.to_string(),
+        }
+    }
+    fn error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, self.error.clone())
+    }
+}
+impl Storage for Unavailable {
+    fn load(&mut self) -> Result<UserData, LoadFailure> {
+        Err(LoadFailure {
+            error: self.error.clone(),
+            quarantined: None,
+        })
+    }
+    fn save(&mut self, _data: &UserData) -> io::Result<()> {
+        Err(self.error())
+    }
+    fn path(&self) -> &Path {
+        Path::new("")
+    }
+    fn read_backup(&self, _path: &Path) -> io::Result<UserData> {
+        Err(self.error())
+    }
+}
+
+/// Converts a database error, treating corruption as damaged data
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    let kind = match &e {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if matches!(failure.code, ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) =>
+        {
+            io::ErrorKind::InvalidData
+        }
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e)
+}
+
+/// Removes a field from a JSON object
+fn take_field(value: &mut Value, field: &str) -> Value {
+    value
+        .as_object_mut()
+        .and_then(|o| o.remove(field))
+        .unwrap_or(Value::Null)
+}
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS sets (
+        id INTEGER PRIMARY KEY,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS cards (
+        set_id INTEGER NOT NULL REFERENCES sets(id) ON DELETE CASCADE,
+        id INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (set_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS review_logs (
+        set_id INTEGER NOT NULL,
+        card_id INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        grade TEXT NOT NULL,
+        direction TEXT NOT NULL,
+        last_interval INTEGER NOT NULL,
+        interval INTEGER NOT NULL,
+        ease INTEGER NOT NULL,
+        FOREIGN KEY (set_id, card_id) REFERENCES cards(set_id, id) ON DELETE CASCADE
+    );
+    CREATE INDEX IF NOT EXISTS review_logs_card ON review_logs (set_id, card_id);
+";
+
+/// Keeps sets, cards and review logs in separate tables of an SQLite database,
+/// so a change to one set only writes that set.
+/// Rows hold the same JSON as [`JsonStorage`], so loading goes through the same migrations.
+pub struct SqliteStorage {
+    path: PathBuf,
+    connection: Option<Connection>,
+    /// Where to import data from if the database is new
+    import_from: JsonStorage,
+}
+impl SqliteStorage {
+    /// The database is opened when it's first used
+    pub const fn new(path: PathBuf, import_from: JsonStorage) -> Self {
+        Self {
+            path,
+            connection: None,
+            import_from,
+        }
+    }
+    fn connect(&mut self) -> io::Result<&mut Connection> {
+        if self.connection.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let connection = Connection::open(&self.path).map_err(sql_error)?;
+            connection.execute_batch(SCHEMA).map_err(sql_error)?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+    /// Rebuilds the JSON form of the user data from the tables
+    fn read(connection: &Connection) -> rusqlite::Result<Option<Value>> {
+        let meta: Option<String> = connection
+            .query_row("SELECT value FROM meta WHERE key = 'user'", [], |r| r.get(0))
+            .optional()?;
+        let mut user: Value = match meta {
+            Some(meta) => serde_json::from_str(&meta).unwrap_or(Value::Null),
+            None => return Ok(None),
+        };
+        let mut sets = vec![];
+        let mut set_query = connection.prepare("SELECT id, data FROM sets ORDER BY position")?;
+        let mut card_query = connection.prepare("SELECT id, data FROM cards WHERE set_id = ? ORDER BY position")?;
+        let mut log_query = connection.prepare(
+            "SELECT time, grade, direction, last_interval, interval, ease FROM review_logs
+             WHERE set_id = ? AND card_id = ? ORDER BY rowid",
+        )?;
+        let set_rows: Vec<(i64, String)> = set_query
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (set_id, data) in set_rows {
+            let mut set: Value = serde_json::from_str(&data).unwrap_or(Value::Null);
+            let card_rows: Vec<(i64, String)> = card_query
+                .query_map([set_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let mut cards = vec![];
+            for (card_id, data) in card_rows {
+                let mut card: Value = serde_json::from_str(&data).unwrap_or(Value::Null);
+                let history: Vec<Value> = log_query
+                    .query_map(params![set_id, card_id], |r| {
+                        Ok(json!({
+                            "time": r.get::<_, i64>(0)?,
+                            "grade": r.get::<_, String>(1)?,
+                            "direction": r.get::<_, String>(2)?,
+                            "last_interval": r.get::<_, i64>(3)?,
+                            "interval": r.get::<_, i64>(4)?,
+                            "ease": r.get::<_, i64>(5)?,
+                        }))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                if let Some(card) = card.as_object_mut() {
+                    card.insert("history".into(), history.into());
+                }
+                cards.push(card);
+            }
+            if let Some(set) = set.as_object_mut() {
+                set.insert("flashcards".into(), cards.into());
+            }
+            sets.push(set);
+        }
+        if let Some(user) = user.as_object_mut() {
+            user.insert("sets".into(), sets.into());
+        }
+        Ok(Some(user))
+    }
+    /// Backs up the database before it's written to
+    fn back_up(&self) {
+        if let Err(e) = persist::backup(&self.path) {
+            warn!("Could not back up user data: {}", e);
+        }
+    }
+    /// Writes the user-level fields
+    fn write_meta(connection: &Connection, data: &UserData) -> io::Result<()> {
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('user', ?)",
+                [serde_json::to_string(&data.meta())?],
+            )
+            .map_err(sql_error)?;
+        Ok(())
+    }
+    /// How many review logs are stored for each card of a set
+    fn stored_logs(connection: &Connection, set_id: i64) -> io::Result<HashMap<i64, usize>> {
+        connection
+            .prepare("SELECT card_id, COUNT(*) FROM review_logs WHERE set_id = ? GROUP BY card_id")
+            .and_then(|mut q| q.query_map([set_id], |r| Ok((r.get(0)?, r.get::<_, i64>(1)? as usize)))?.collect())
+            .map_err(sql_error)
+    }
+    /// Writes a card, and whichever of its review logs come after the `stored` ones
+    fn write_card(connection: &Connection, set_id: i64, position: usize, mut card: Value, stored: usize) -> io::Result<()> {
+        let card_id = card.get("id").and_then(Value::as_i64).unwrap_or(0);
+        let history = match take_field(&mut card, "history") {
+            Value::Array(history) => history,
+            _ => vec![],
+        };
+        connection
+            .execute(
+                "INSERT INTO cards (set_id, id, position, data) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (set_id, id) DO UPDATE SET position = ?3, data = ?4",
+                params![set_id, card_id, position as i64, serde_json::to_string(&card)?],
+            )
+            .map_err(sql_error)?;
+        // History is only ever added to, so only the new entries need writing
+        for log in history.iter().skip(stored) {
+            let field = |name: &str| log.get(name).cloned().unwrap_or(Value::Null);
+            let text = |name: &str| field(name).as_str().unwrap_or_default().to_owned();
+            let number = |name: &str| field(name).as_i64().unwrap_or_default();
+            connection
+                .execute(
+                    "INSERT INTO review_logs (set_id, card_id, time, grade, direction, last_interval, interval, ease)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        set_id,
+                        card_id,
+                        number("time"),
+                        text("grade"),
+                        text("direction"),
+                        number("last_interval"),
+                        number("interval"),
+                        number("ease"),
+                    ],
+                )
+                .map_err(sql_error)?;
+        }
+        Ok(())
+    }
+    /// Writes a set and its cards, and any review logs that aren't stored yet
+    fn write_set(connection: &Connection, position: usize, mut set: Value) -> io::Result<()> {
+        let id = set.get("id").and_then(Value::as_i64).unwrap_or(0);
+        let name = set.get("name").and_then(Value::as_str).unwrap_or("").to_owned();
+        let cards = match take_field(&mut set, "flashcards") {
+            Value::Array(cards) => cards,
+            _ => vec![],
+        };
+        connection
+            .execute(
+                "INSERT INTO sets (id, position, name, data) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (id) DO UPDATE SET position = ?2, name = ?3, data = ?4",
+                params![id, position as i64, name, serde_json::to_string(&set)?],
+            )
+            .map_err(sql_error)?;
+        let card_ids: Vec<i64> = cards
+            .iter()
+            .filter_map(|c| c.get("id").and_then(Value::as_i64))
+            .collect();
+        // Cards that have been removed from the set
+        let stored: Vec<i64> = connection
+            .prepare("SELECT id FROM cards WHERE set_id = ?")
+            .and_then(|mut q| q.query_map([id], |r| r.get(0))?.collect())
+            .map_err(sql_error)?;
+        for removed in stored.iter().filter(|c| !card_ids.contains(c)) {
+            connection
+                .execute("DELETE FROM cards WHERE set_id = ? AND id = ?", [id, *removed])
+                .map_err(sql_error)?;
+        }
+        let stored = Self::stored_logs(connection, id)?;
+        for (position, card) in cards.into_iter().enumerate() {
+            let card_id = card.get("id").and_then(Value::as_i64).unwrap_or(0);
+            let stored = stored.get(&card_id).copied().unwrap_or(0);
+            Self::write_card(connection, id, position, card, stored)?;
+        }
+        Ok(())
+    }
+    /// Updates the order of the sets
+    fn write_positions(connection: &Connection, data: &UserData) -> io::Result<()> {
+        for (position, set) in data.sets.iter().enumerate() {
+            connection
+                .execute(
+                    "UPDATE sets SET position = ? WHERE id = ?",
+                    params![position as i64, u64::from(set.id()) as i64],
+                )
+                .map_err(sql_error)?;
+        }
+        Ok(())
+    }
+}
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<UserData, LoadFailure> {
+        info!("Loading user data from {}", self.path.display());
+        let path = self.path.clone();
+        let stored = self
+            .connect()
+            .and_then(|c| Self::read(c).map_err(sql_error))
+            .map_err(|e| {
+                // Close the database before moving it aside
+                self.connection = None;
+                load_failure(&path, e)
+            })?;
+        match stored {
+            Some(value) => UserData::from_json(value).map_err(|e| {
+                self.connection = None;
+                load_failure(&path, e)
+            }),
+            None => {
+                check_not_quarantined(&path)?;
+                // A new database - bring over any data saved as JSON
+                let data = if self.import_from.path().exists() {
+                    info!("Importing {} into the database", self.import_from.path().display());
+                    self.import_from.load()?
+                } else {
+                    UserData::default()
+                };
+                self.save(&data).map_err(|e| load_failure(&path, e))?;
+                Ok(data)
+            }
+        }
+    }
+    fn save(&mut self, data: &UserData) -> io::Result<()> {
+        info!("Saving all user data");
+        self.back_up();
+        let connection = self.connect()?;
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction
+            .execute_batch("DELETE FROM review_logs; DELETE FROM cards; DELETE FROM sets;")
+            .map_err(sql_error)?;
+        Self::write_meta(&transaction, data)?;
+        for (position, set) in data.sets.iter().enumerate() {
+            Self::write_set(&transaction, position, serde_json::to_value(set)?)?;
+        }
+        transaction.commit().map_err(sql_error)
+    }
+    fn save_set(&mut self, data: &UserData, set: SetId) -> io::Result<()> {
+        let position = match data.sets.iter().position(|s| s.id() == set) {
+            Some(position) => position,
+            None => return self.remove_set(data, set),
+        };
+        let value = serde_json::to_value(&data.sets[position])?;
+        self.back_up();
+        let connection = self.connect()?;
+        let transaction = connection.transaction().map_err(sql_error)?;
+        Self::write_set(&transaction, position, value)?;
+        Self::write_positions(&transaction, data)?;
+        // New sets move on the next set id
+        Self::write_meta(&transaction, data)?;
+        transaction.commit().map_err(sql_error)
+    }
+    fn save_cards(&mut self, data: &UserData, set: SetId, cards: &[u64]) -> io::Result<()> {
+        let flashcards = match data.set(set) {
+            Some(flashcards) => &flashcards.flashcards,
+            // Removed since - that's saved separately
+            None => return Ok(()),
+        };
+        self.back_up();
+        let set_id = u64::from(set) as i64;
+        let connection = self.connect()?;
+        let transaction = connection.transaction().map_err(sql_error)?;
+        let stored = Self::stored_logs(&transaction, set_id)?;
+        for (position, card) in flashcards.iter().enumerate().filter(|(_, c)| cards.contains(&c.id())) {
+            let logs = stored.get(&(card.id() as i64)).copied().unwrap_or(0);
+            Self::write_card(&transaction, set_id, position, serde_json::to_value(card)?, logs)?;
+        }
+        transaction.commit().map_err(sql_error)
+    }
+    fn remove_set(&mut self, data: &UserData, set: SetId) -> io::Result<()> {
+        self.back_up();
+        let connection = self.connect()?;
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction
+            .execute("DELETE FROM sets WHERE id = ?", [u64::from(set) as i64])
+            .map_err(sql_error)?;
+        Self::write_positions(&transaction, data)?;
+        transaction.commit().map_err(sql_error)
+    }
+    fn save_meta(&mut self, data: &UserData) -> io::Result<()> {
+        self.back_up();
+        Self::write_meta(self.connect()?, data)
+    }
+    fn path(&self) -> &Path {
+        &self.path
+    }
+    fn read_backup(&self, path: &Path) -> io::Result<UserData> {
+        // Reading a backup mustn't change it
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sql_error)?;
+        let value = Self::read(&connection)
+            .map_err(sql_error)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "backup is empty"))?;
+        UserData::from_json(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::schedule::{Direction, Grade};
+
+    #[test]
+    fn damaged_data_stays_unrecovered_until_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user-data.json");
+        fs::write(&path, "{ not json").unwrap();
+        let mut storage = JsonStorage::new(path.clone());
+        let failure = storage.load().err().unwrap();
+        assert!(failure.quarantined.is_some());
+        assert!(!path.exists());
+
+        // Opening again mustn't start over with the example sets
+        let failure = storage.load().err().unwrap();
+        assert_eq!(failure.quarantined, persist::quarantined(&path).into_iter().next());
+        assert!(!path.exists());
+
+        // Once something's been recovered and saved, it loads as normal
+        storage.save(&UserData::empty()).unwrap();
+        assert!(storage.load().is_ok());
+    }
+
+    /// An SQLite database in `dir`, loaded with the example sets
+    fn sqlite(dir: &Path) -> (SqliteStorage, UserData) {
+        let mut storage = SqliteStorage::new(dir.join("user-data.sqlite3"), JsonStorage::new(dir.join("user-data.json")));
+        let data = storage.load().unwrap();
+        (storage, data)
+    }
+
+    #[test]
+    fn sqlite_saves_reviews_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, mut data) = sqlite(dir.path());
+        let set = data.sets[0].id();
+        let card = data.sets[0].flashcards[1].id();
+        data.set_mut(set).unwrap()[card].record_review(Direction::Forward, Grade::Good, 1000);
+        storage.save_cards(&data, set, &[card]).unwrap();
+        data.set_mut(set).unwrap()[card].record_review(Direction::Forward, Grade::Easy, 2000);
+        data.study_time += Duration::from_secs(60);
+        // Saving the whole set afterwards only adds the newer review
+        storage.save_set(&data, set).unwrap();
+        storage.save_meta(&data).unwrap();
+
+        let (_, loaded) = sqlite(dir.path());
+        let loaded_card = &loaded.set(set).unwrap()[card];
+        assert_eq!(loaded_card.history, data.set(set).unwrap()[card].history);
+        assert_eq!(loaded_card.review, data.set(set).unwrap()[card].review);
+        assert_eq!(loaded.study_time, Duration::from_secs(60));
+        assert_eq!(loaded.sets.len(), data.sets.len());
+    }
+
+    #[test]
+    fn sqlite_backups_are_read_without_changing_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = sqlite(dir.path());
+        let backup = dir.path().join("empty.sqlite3");
+        Connection::open(&backup).unwrap();
+        let before = fs::read(&backup).unwrap();
+        assert!(storage.read_backup(&backup).is_err());
+        assert_eq!(fs::read(&backup).unwrap(), before);
+        assert_eq!(storage.read_backup(storage.path()).unwrap().sets.len(), 2);
+    }
+}
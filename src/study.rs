@@ -1,16 +1,53 @@
-use std::{cell::Ref, lazy::OnceCell};
+use std::{cell::{Cell, Ref, RefCell}, time::{Duration, Instant}};
 
-use dioxus::{prelude::*, fermi::{use_read, use_set}};
-use rand::{prelude::SliceRandom, thread_rng};
+use dioxus::{prelude::*, fermi::{use_read, use_set, Atom}};
+use rand::thread_rng;
 
-use crate::{USER_DATA, CURRENT_PAGE, CurrentPage};
+use crate::{
+    USER_DATA, CURRENT_PAGE, CurrentPage,
+    answer::{self, DiffKind, Verdict},
+    data::{SetId, UserDataAccessor},
+    media,
+    rich_text,
+    schedule::{self, Grade, StudyDirection},
+    session::{Phase, StudySession},
+};
+
+/// How the user answers each card
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum StudyMode {
+    /// Reveal the back and grade yourself
+    SelfGraded,
+    /// Type the back - graded by how close the answer is
+    Typed {
+        /// Whether accents are ignored when checking
+        fold_accents: bool,
+    },
+    /// Pick the back from a list of options taken from the rest of the set
+    MultipleChoice {
+        /// How many options are shown
+        options: usize,
+        /// Whether to prefer options that look like the right answer
+        similar: bool,
+    },
+}
 
 
 /// Study page
 pub fn Study(cx: Scope) -> Element {
     let (time, set_time) = use_state(&cx, || 20u8);
-    let sets = Ref::map(use_read(&cx, USER_DATA).borrow(), |u| &u.get().sets);
-    let (chosen, set_chosen) = use_state(&cx, || sets[0].name.clone());
+    let user_data = use_read(&cx, USER_DATA);
+    let sets = Ref::map(user_data.borrow(), |u| &u.get().sets);
+    let (chosen, set_chosen) = use_state(&cx, || sets.first().map(|s| s.id()));
+    let (mode, set_mode) = use_state(&cx, || StudyMode::SelfGraded);
+    // None uses the chosen set's own direction
+    let (direction, set_direction) = use_state(&cx, || None::<StudyDirection>);
+    let autoplay = user_data.borrow().get().autoplay_audio;
+    let set_direction_label = sets
+        .iter()
+        .find(|s| Some(s.id()) == *chosen)
+        .map(|s| s.direction.label())
+        .unwrap_or_default();
     rsx!(cx, div {
         class: "center-div",
         h1 {"Study"}
@@ -29,23 +66,95 @@ pub fn Study(cx: Scope) -> Element {
         "{time} minutes"
         select {
             oninput: move |e| {
-                set_chosen(e.value.clone());
+                set_chosen(e.value.parse().ok());
             },
             sets.iter().map(|s|
                 rsx! {
                     option {
-                        value: "{s.name}",
-                        key: "{s.name}",
+                        value: "{s.id()}",
+                        key: "{s.id()}",
                         "{s.name}"
                     }
                 }
             )
         }
+        select {
+            oninput: move |e| {
+                set_mode(match e.value.as_str() {
+                    "typed" => StudyMode::Typed { fold_accents: true },
+                    "choice" => StudyMode::MultipleChoice { options: 4, similar: false },
+                    _ => StudyMode::SelfGraded,
+                });
+            },
+            option { value: "self", "Reveal and grade yourself" }
+            option { value: "typed", "Type the answer" }
+            option { value: "choice", "Multiple choice" }
+        }
+        match *mode {
+            StudyMode::SelfGraded => None,
+            StudyMode::Typed { fold_accents } => rsx!(cx, label {
+                input {
+                    "type": "checkbox",
+                    checked: "{fold_accents}",
+                    oninput: move |_| set_mode(StudyMode::Typed { fold_accents: !fold_accents })
+                }
+                "Ignore accents"
+            }),
+            StudyMode::MultipleChoice { options, similar } => rsx!(cx, div {
+                input {
+                    "type": "number",
+                    min: "2",
+                    max: "8",
+                    value: "{options}",
+                    oninput: move |e| if let Ok(options) = e.value.parse() {
+                        set_mode(StudyMode::MultipleChoice { options, similar });
+                    }
+                }
+                " options "
+                label {
+                    input {
+                        "type": "checkbox",
+                        checked: "{similar}",
+                        oninput: move |_| set_mode(StudyMode::MultipleChoice { options, similar: !similar })
+                    }
+                    "Similar-looking options"
+                }
+            }),
+        }
+        select {
+            oninput: move |e| set_direction(StudyDirection::from_name(&e.value)),
+            option { value: "set", "Set default ({set_direction_label})" }
+            StudyDirection::ALL.iter().map(|d| rsx!(cx, option {
+                key: "{d.name()}",
+                value: "{d.name()}",
+                "{d.label()}"
+            }))
+        }
+        label {
+            input {
+                "type": "checkbox",
+                checked: "{autoplay}",
+                oninput: move |_| {
+                    user_data.borrow_mut().modify_meta(|d| d.autoplay_audio = !autoplay);
+                    cx.needs_update();
+                }
+            }
+            "Play sounds when a side is shown"
+        }
         button {
             "type": "button",
+            disabled: "{chosen.is_none()}",
             onclick: move |_| {
+                let data = user_data.borrow();
+                let set = match chosen.and_then(|id| data.get().set(id)) {
+                    Some(set) => set,
+                    None => return,
+                };
                 use_set(&cx, CURRENT_PAGE)(CurrentPage::FlashcardTester (FlashcardTesterProps {
-                    set: chosen.clone()
+                    set: set.id(),
+                    minutes: *time,
+                    mode: *mode,
+                    direction: direction.unwrap_or(set.direction)
                 }));
             },
             "Go!"
@@ -54,36 +163,336 @@ pub fn Study(cx: Scope) -> Element {
 }
 #[derive(Props, PartialEq, Debug, Clone)]
 pub struct FlashcardTesterProps {
-    set: String
+    set: SetId,
+    /// Study time budget in minutes
+    minutes: u8,
+    mode: StudyMode,
+    direction: StudyDirection
+}
+impl FlashcardTesterProps {
+    /// The study time budget
+    fn budget(&self) -> Duration {
+        Duration::from_secs(u64::from(self.minutes) * 60)
+    }
+}
+
+/// Formats a duration as minutes and seconds
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// When the session being studied started, until its time has been added to the study time
+pub static STUDYING_SINCE: Atom<Cell<Option<Instant>>> = |_| Cell::new(None);
+
+/// Adds the time spent so far in a session that's left before it finishes to the study time
+pub fn record_unfinished_session(studying_since: &Cell<Option<Instant>>, user_data: &RefCell<UserDataAccessor>) {
+    if let Some(started) = studying_since.take() {
+        user_data.borrow_mut().modify_meta(|d| d.study_time += started.elapsed());
+    }
+}
+
+/// Starts timing a new session, unless it has nothing to study
+fn start_timing(session: StudySession, studying_since: &Cell<Option<Instant>>) -> StudySession {
+    studying_since.set((session.phase() != Phase::Finished).then(|| session.started()));
+    session
+}
+
+/// Takes the time spent in a finished session, once, and stops timing it
+fn take_finished_time(session: &mut StudySession, studying_since: &Cell<Option<Instant>>) -> Option<Duration> {
+    let spent = session.take_time_spent()?;
+    studying_since.set(None);
+    Some(spent)
+}
+
+/// Adds the time spent in the session to the study time, once it's finished
+fn record_finished_session(session: &UseRef<StudySession>, studying_since: &Cell<Option<Instant>>, user_data: &RefCell<UserDataAccessor>) {
+    let spent = take_finished_time(&mut session.write(), studying_since);
+    if let Some(spent) = spent {
+        user_data.borrow_mut().modify_meta(|d| d.study_time += spent);
+    }
+}
+
+/// Grades the current card of the session, saving its new review state (and the time spent, if finished)
+fn grade_card(session: &UseRef<StudySession>, studying_since: &Cell<Option<Instant>>, user_data: &RefCell<UserDataAccessor>, set: SetId, grade: Grade) {
+    let graded = session.write().grade(grade);
+    if let Some(prompt) = graded {
+        user_data.borrow_mut().modify_card(set, prompt.card, |card| {
+            card.record_review(prompt.direction, grade, schedule::now());
+        });
+        record_finished_session(session, studying_since, user_data);
+    }
 }
 /// Tests the user on a flashcard set
 pub fn FlashcardTester(cx: Scope<FlashcardTesterProps>) -> Element {
-    let sets = Ref::map(use_read(&cx, USER_DATA).borrow(), |u| &u.get().sets);
-    let target_set = sets.iter().find(|s| s.name == cx.props.set).unwrap();
-    let queue = use_ref(&cx, || {
-        let mut queue = target_set.flashcards.iter().map(|f| f.id()).collect::<Vec<u64>>();
-        queue.shuffle(&mut thread_rng());
-        let cell = OnceCell::new();
-        cell.set(queue).unwrap();
-        cell
+    let user_data = use_read(&cx, USER_DATA);
+    let data = Ref::map(user_data.borrow(), |u| u.get());
+    let target_set = match data.set(cx.props.set) {
+        Some(set) => set,
+        None => return rsx!(cx, div {
+            class: "center-div",
+            p {"This set doesn't exist any more"}
+        }),
+    };
+    let (typed_answer, set_typed_answer) = use_state(&cx, String::new);
+    // Multiple choice options for the current turn: (session start, turn, options, index of the right option)
+    let choices = use_ref(&cx, || None::<((Instant, u32), Vec<String>, usize)>);
+    let (picked, set_picked) = use_state(&cx, || None::<usize>);
+    let studying_since = use_read(&cx, STUDYING_SINCE);
+    // Leaving before the end still counts, see `record_unfinished_session`
+    let session = use_ref(&cx, || start_timing(
+        StudySession::new(
+            schedule::due_cards(target_set, cx.props.direction, schedule::now(), schedule::NEW_CARDS_PER_SESSION),
+            cx.props.budget()
+        ),
+        studying_since
+    ));
+    // Re-render every second to keep the countdown ticking
+    let update = cx.schedule_update();
+    use_future(&cx, || async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            update();
+        }
     });
-    let current_card_id = queue.read().get().unwrap()[0];
-    let current_card = &target_set[current_card_id];
+    let phase = session.read().phase();
+    let prompt = match session.read().current() {
+        Some(prompt) if phase != Phase::Finished => prompt,
+        _ => {
+            let summary = session.read().summary().clone();
+            let time_spent = format_duration(session.read().time_spent());
+            if summary.reviews() == 0 {
+                return rsx!(cx, div {
+                    class: "center-div",
+                    h1 {"{target_set.name}"}
+                    p {"Nothing to review right now - come back later!"}
+                });
+            }
+            let failed = summary.failed.iter().map(|p| {
+                let card = &target_set[p.card];
+                let question = &card.prompt(p.direction).text;
+                let answer = &card.answer(p.direction).text;
+                rsx!(cx, li { key: "{p.card}-{p.direction:?}", "{question} - {answer}" })
+            });
+            return rsx!(cx, div {
+                class: "center-div",
+                h1 {"{target_set.name}"}
+                h2 {"Session complete"}
+                p {"{summary.reviews()} reviews in {time_spent}"}
+                div {
+                    class: "row-flex grade-buttons",
+                    Grade::ALL.iter().map(|g| {
+                        let count = summary.count(*g);
+                        rsx!(cx, p { key: "{g:?}", "{g:?}: {count}" })
+                    })
+                }
+                (!summary.failed.is_empty()).then(|| rsx!(cx,
+                    h2 {"Forgotten cards"}
+                    ul { failed }
+                ))
+                button {
+                    "type": "button",
+                    onclick: move |_| {
+                        let data = user_data.borrow();
+                        if let Some(set) = data.get().set(cx.props.set) {
+                            // Nothing from the last session's final card carries over
+                            *choices.write() = None;
+                            set_picked(None);
+                            set_typed_answer(String::new());
+                            *session.write() = start_timing(
+                                StudySession::new(
+                                    schedule::due_cards(set, cx.props.direction, schedule::now(), schedule::NEW_CARDS_PER_SESSION),
+                                    cx.props.budget()
+                                ),
+                                studying_since
+                            );
+                        }
+                    },
+                    "Study again"
+                }
+            });
+        }
+    };
+    let current_card = &target_set[prompt.card];
+    let question = current_card.prompt(prompt.direction);
+    let expected = current_card.answer(prompt.direction);
+    let remaining = session.read().remaining();
+    let time_left = format_duration(session.read().time_left());
+    let out_of_time = session.read().is_out_of_time();
+    // The answer's sounds start when it's revealed, as that's when they're first shown
+    let autoplay = data.autoplay_audio;
+    // A picture or a sound can't be typed or picked from a list of text, so those cards are graded by hand
+    let mode = if answer::answerable(&expected.text) { cx.props.mode } else { StudyMode::SelfGraded };
     rsx!(cx, div {
         class: "center-div",
         h1 {"{target_set.name}"}
+        p {"{remaining} cards left - {time_left} remaining"}
+        out_of_time.then(|| rsx!(cx, p {
+            class: "warning",
+            "Time's up! This is the last card."
+        }))
         div {
             class: "row-flex",
             div {
                 class: "test-flashcard",
-                style: "color: {current_card.front.color};",
-                "{current_card.front.text}"
+                rich_text::RichTextView { text: question.clone() }
+                media::Images { images: question.images.clone() }
+                media::Sounds { clips: question.audio.clone(), autoplay: autoplay }
             }
-            div {
+            (phase == Phase::Answer).then(|| rsx!(cx, div {
                 class: "test-flashcard",
-                style: "color: {current_card.back.color};",
-                "{current_card.back.text}"
+                rich_text::RichTextView { text: expected.clone() }
+                media::Images { images: expected.images.clone() }
+                media::Sounds { clips: expected.audio.clone(), autoplay: autoplay }
+            }))
+        }
+        (mode != cx.props.mode).then(|| rsx!(cx, p {
+            "This answer is a picture or a sound, so grade yourself on it."
+        }))
+        match (mode, phase) {
+            (StudyMode::SelfGraded, Phase::Question) => rsx!(cx, button {
+                "type": "button",
+                onclick: move |_| session.write().reveal(),
+                "Show answer"
+            }),
+            (StudyMode::SelfGraded, _) => rsx!(cx, div {
+                class: "row-flex grade-buttons",
+                Grade::ALL.iter().map(|g| {
+                    let grade = *g;
+                    rsx!(cx, button {
+                        key: "{grade:?}",
+                        "type": "button",
+                        class: "grade-button",
+                        onclick: move |_| grade_card(session, studying_since, user_data, cx.props.set, grade),
+                        "{grade:?}"
+                    })
+                })
+            }),
+            (StudyMode::Typed { .. }, Phase::Question) => rsx!(cx, div {
+                class: "row-flex grade-buttons",
+                input {
+                    "type": "text",
+                    class: "typed-answer",
+                    value: "{typed_answer}",
+                    oninput: move |e| set_typed_answer(e.value.clone())
+                }
+                button {
+                    "type": "button",
+                    onclick: move |_| session.write().reveal(),
+                    "Check"
+                }
+            }),
+            (StudyMode::Typed { fold_accents }, _) => {
+                let score = answer::score(&expected.text, typed_answer, fold_accents);
+                let verdict = match score.verdict {
+                    Verdict::Correct => "Correct!",
+                    Verdict::Close => "Almost - check the differences",
+                    Verdict::Wrong => "Incorrect",
+                };
+                let grade = score.verdict.grade();
+                rsx!(cx, div {
+                    class: "center-div",
+                    p {"{verdict}"}
+                    p {
+                        class: "answer-diff",
+                        score.diff.iter().enumerate().map(|(i, span)| {
+                            let class = match span.kind {
+                                DiffKind::Same => "diff-same",
+                                DiffKind::Missing => "diff-missing",
+                                DiffKind::Extra => "diff-extra",
+                            };
+                            rsx!(cx, span { key: "{i}", class: "{class}", "{span.text}" })
+                        })
+                    }
+                    button {
+                        "type": "button",
+                        onclick: move |_| {
+                            set_typed_answer(String::new());
+                            grade_card(session, studying_since, user_data, cx.props.set, grade);
+                        },
+                        "Next"
+                    }
+                })
             }
+            (StudyMode::MultipleChoice { options, similar }, _) => {
+                // New options every turn, so they don't reshuffle on every render
+                let turn = (session.read().started(), session.read().summary().reviews());
+                if choices.read().as_ref().map(|c| c.0) != Some(turn) {
+                    let (options, correct) = answer::choices(target_set, prompt, options, similar, &mut thread_rng());
+                    *choices.write() = Some((turn, options, correct));
+                }
+                let (options, correct) = match choices.read().as_ref() {
+                    Some((_, options, correct)) => (options.clone(), *correct),
+                    None => return None,
+                };
+                rsx!(cx, div {
+                    class: "center-div",
+                    options.into_iter().enumerate().map(|(i, option)| {
+                        let class = match (phase, *picked) {
+                            (Phase::Answer, _) if i == correct => "choice choice-correct",
+                            (Phase::Answer, Some(p)) if p == i => "choice choice-wrong",
+                            _ => "choice",
+                        };
+                        rsx!(cx, button {
+                            key: "{i}",
+                            "type": "button",
+                            class: "{class}",
+                            disabled: "{phase == Phase::Answer}",
+                            onclick: move |_| {
+                                set_picked(Some(i));
+                                session.write().reveal();
+                            },
+                            "{option}"
+                        })
+                    })
+                    (phase == Phase::Answer).then(|| rsx!(cx, button {
+                        "type": "button",
+                        onclick: move |_| {
+                            let grade = if *picked == Some(correct) { Grade::Good } else { Grade::Again };
+                            set_picked(None);
+                            grade_card(session, studying_since, user_data, cx.props.set, grade);
+                        },
+                        "Next"
+                    }))
+                })
+            }
+        }
+        button {
+            "type": "button",
+            onclick: move |_| {
+                session.write().finish();
+                record_finished_session(session, studying_since, user_data);
+            },
+            "End session"
         }
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{Direction, Prompt};
+
+    fn one_card() -> StudySession {
+        let prompt = Prompt { card: 0, direction: Direction::Forward };
+        StudySession::new(vec![prompt], Duration::from_secs(60))
+    }
+
+    #[test]
+    fn studying_again_is_timed_too() {
+        let studying_since = Cell::new(None);
+        let mut first = start_timing(one_card(), &studying_since);
+        assert_eq!(studying_since.get(), Some(first.started()));
+        first.reveal();
+        first.grade(Grade::Good);
+        assert!(take_finished_time(&mut first, &studying_since).is_some());
+        assert_eq!(studying_since.get(), None);
+
+        // "Study again" - leaving this one early has to find its start
+        let second = start_timing(one_card(), &studying_since);
+        assert_eq!(studying_since.get(), Some(second.started()));
+        // Nothing to study, so nothing to time
+        start_timing(StudySession::new(vec![], Duration::from_secs(60)), &studying_since);
+        assert_eq!(studying_since.get(), None);
+    }
+}